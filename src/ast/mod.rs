@@ -41,12 +41,37 @@ impl AstNode {
         &mut self.children
     }
 
+    /// Check if this node trivially has no content, without evaluating it.
+    /// References and function calls count as content even if they would
+    /// expand to nothing, like the unexpanded value of a variable in GNU make.
+    pub fn is_empty(&self) -> bool {
+        match self.children() {
+            AstChildren::Empty => true,
+            AstChildren::Constant(s) => s.is_empty(),
+            AstChildren::PreEvaluated(block) => block.len() == 0,
+            AstChildren::Concat(children) => children.iter().all(AstNode::is_empty),
+            _ => false,
+        }
+    }
+
     /// Evaluate this AST, in the context of the provided database. The provided
     /// database *is not* updated. To fully commit the changes of this
     /// evaluation, one must use `Engine::replace_database` with the returned
     /// database.
     pub fn eval(&self, names: &mut NameCache, context: &mut Engine) -> Arc<Block> {
-        let (sensitivity, content) = self.eval_internal(names, context);
+        self.eval_within(names, context, &Default::default())
+    }
+
+    /// Evaluate this AST as part of a makefile line whose interpretation
+    /// depends on `enclosing`. Makefile text read by an `$(eval)` is globally
+    /// sensitive to those variables too.
+    pub(crate) fn eval_within(
+        &self,
+        names: &mut NameCache,
+        context: &mut Engine,
+        enclosing: &Set<VariableName>,
+    ) -> Arc<Block> {
+        let (sensitivity, content) = self.eval_internal(names, context, enclosing);
 
         Block::new(sensitivity, content)
     }
@@ -56,6 +81,7 @@ impl AstNode {
         &self,
         names: &mut NameCache,
         context: &mut Engine,
+        enclosing: &Set<VariableName>,
     ) -> (Set<VariableName>, Vec<ContentReference>) {
         let mut sensitivity: Set<VariableName> = Default::default();
         // This is technically a little inefficient (we don't always need to do
@@ -74,7 +100,7 @@ impl AstNode {
         macro_rules! eval_child {
             ($child:expr) => {{
                 let child = $child;
-                let (new_sens, child_content) = child.eval_internal(names, context);
+                let (new_sens, child_content) = child.eval_internal(names, context, enclosing);
                 merge_sensitivity!(new_sens);
 
                 child_content
@@ -83,7 +109,7 @@ impl AstNode {
 
         macro_rules! eval_subexpr {
             ($e: expr) => {{
-                let block = ($e).eval(names, context);
+                let block = ($e).eval_within(names, context, enclosing);
                 merge_sensitivity!(block.raw_sensitivity());
 
                 block
@@ -119,7 +145,10 @@ impl AstNode {
                 }
                 contents
             }
-            AstChildren::PreEvaluated(ref block) => block.content().map(|x| x.clone()).collect(),
+            AstChildren::PreEvaluated(ref block) => {
+                merge_sensitivity!(block.raw_sensitivity());
+                block.content().map(|x| x.clone()).collect()
+            }
             AstChildren::VariableReference(name) => {
                 // Compute the variable name, and then try to evaluate it
                 let name = eval_subexpr!(name);
@@ -173,13 +202,25 @@ impl AstNode {
 
                 debug!("Processing block from eval content");
                 context
-                    .process_block(names, &block)
+                    .process_block_within(names, &block, enclosing.clone())
                     .ok()
                     .expect("TODO: error routing for this parse");
 
                 // The eval already consumed the content, don't try to keep parsing it
                 vec![]
             }
+            AstChildren::Dir(names) => {
+                let names = eval_subexpr!(names);
+                let dirs = text_functions::dir(sensitivity.clone(), names.span());
+
+                dirs.content().cloned().collect()
+            }
+            AstChildren::LastWord(words) => {
+                let words = eval_subexpr!(words);
+                let last = text_functions::last_word(sensitivity.clone(), words.span());
+
+                last.content().cloned().collect()
+            }
             AstChildren::Empty => {
                 // Empty children generate no content
                 Vec::new()
@@ -227,6 +268,12 @@ pub enum AstChildren {
     /// The `words` make function
     // #SPC-V-AST.words
     Words(AstNode),
+    /// The `dir` make function
+    // #SPC-V-AST.dir
+    Dir(AstNode),
+    /// The `lastword` make function
+    // #SPC-V-AST.lastword
+    LastWord(AstNode),
 }
 
 /// Get an empty AST node. These are usually useful as placeholders
@@ -311,6 +358,24 @@ pub fn words(source_location: Location, arg: AstNode) -> AstNode {
     }
 }
 
+/// Create a new `dir` node
+#[inline]
+pub fn dir(source_location: Location, names: AstNode) -> AstNode {
+    AstNode {
+        children: Box::new(AstChildren::Dir(names)),
+        source_location: source_location.into(),
+    }
+}
+
+/// Create a new `lastword` node
+#[inline]
+pub fn last_word(source_location: Location, words: AstNode) -> AstNode {
+    AstNode {
+        children: Box::new(AstChildren::LastWord(words)),
+        source_location: source_location.into(),
+    }
+}
+
 /// Create a new `word` node
 #[inline]
 pub fn word(source_location: Location, index: AstNode, words: AstNode) -> AstNode {
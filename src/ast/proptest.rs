@@ -28,6 +28,8 @@ pub fn arb_ast(tree_breadth: u32) -> impl Strategy<Value = AstNode> {
             (inner.clone(), inner.clone())
                 .prop_map(|(a, b)| AstChildren::Word { index: a, words: b }),
             inner.clone().prop_map(AstChildren::Words),
+            inner.clone().prop_map(AstChildren::Dir),
+            inner.clone().prop_map(AstChildren::LastWord),
         ]
     });
     child.prop_map(|child| {
@@ -124,6 +126,18 @@ pub fn arb_flat_ast(ast_breadth: u32) -> impl Strategy<Value = (AstNode, String)
             self.super_words(words);
             self.visit_function_post();
         }
+
+        fn visit_dir(&mut self, names: &'a mut AstNode) {
+            self.visit_function_pre("dir");
+            self.super_dir(names);
+            self.visit_function_post();
+        }
+
+        fn visit_last_word(&mut self, words: &'a mut AstNode) {
+            self.visit_function_pre("lastword");
+            self.super_last_word(words);
+            self.visit_function_post();
+        }
     }
 
     let ast = arb_ast(ast_breadth);
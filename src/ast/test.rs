@@ -31,7 +31,12 @@ fn insert_variable_from_line(
     use crate::parsers::variable::Action;
 
     let block = block_from_reference(evaluated::constant(variable));
-    let (remaining, variable_op) = assert_ok!(parse_variable_line(block.span(), names, context));
+    let (remaining, variable_op) = assert_ok!(parse_variable_line(
+        block.span(),
+        names,
+        context,
+        &Default::default()
+    ));
     assert_complete!(remaining);
 
     match variable_op.action {
@@ -313,3 +318,40 @@ fn var_ref_recursive() {
         )
     )
 }
+
+#[test]
+fn dir_function() {
+    let block = single_block("$(dir src/foo.c hacks lib/x/y.h)");
+    let ast = ast_parse!(block);
+
+    let mut engine = Default::default();
+    let mut names = Default::default();
+    let evaluated = ast.eval(&mut names, &mut engine);
+
+    assert_eq!(evaluated.into_string(), "src/ ./ lib/x/");
+    // The directory parts should still point back at the original text
+    assert_eq!(
+        evaluated.span().location(),
+        Some(crate::source_location::Location::test_location(1, 7))
+    );
+}
+
+#[test]
+fn last_word_function() {
+    let block = single_block("$(lastword foo.mk  sub/bar.mk )");
+    let ast = ast_parse!(block);
+
+    let mut engine = Default::default();
+    let mut names = Default::default();
+    let evaluated = ast.eval(&mut names, &mut engine);
+
+    assert_eq!(evaluated.into_string(), "sub/bar.mk");
+    assert_eq!(
+        evaluated.span().location(),
+        Some(crate::source_location::Location::test_location(1, 20))
+    );
+
+    let block = single_block("$(lastword  )");
+    let ast = ast_parse!(block);
+    assert_eq!(ast.eval(&mut names, &mut engine).into_string(), "");
+}
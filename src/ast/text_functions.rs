@@ -115,3 +115,58 @@ fn do_replacement<'a>(
 
     output
 }
+
+/// Implementation of the `dir` function. Every whitespace-separated name is
+/// replaced by everything up to and including its last `/`, or `./` if there
+/// is no `/` in the name.
+pub(super) fn dir(sensitivity: Set<VariableName>, mut names: BlockSpan) -> Arc<Block> {
+    use crate::source_location::LocatedString;
+    use nom::Slice;
+
+    let mut output = Block::new(sensitivity, Default::default());
+    let mut first = true;
+    while names.len() > 0 {
+        let (new_names, name) = makefile_token(names).expect("tokens should never fail");
+        names = new_names;
+        if name.len() == 0 {
+            // Only trailing whitespace was left
+            continue;
+        }
+
+        if !first {
+            Arc::make_mut(&mut output).push(crate::evaluated::ContentReference::space());
+        } else {
+            first = false;
+        }
+
+        match name.char_indices().filter(|(_, c)| *c == '/').last() {
+            Some((idx, _)) => Arc::make_mut(&mut output).push_all_contents(name.slice(..idx + 1)),
+            None => Arc::make_mut(&mut output).push(crate::evaluated::constant(
+                LocatedString::synthetic_new("./"),
+            )),
+        }
+    }
+    Arc::make_mut(&mut output).simplify();
+
+    output
+}
+
+/// Implementation of the `lastword` function: the last whitespace-separated
+/// word of the input, or nothing if there are no words.
+pub(super) fn last_word(sensitivity: Set<VariableName>, mut words: BlockSpan) -> Arc<Block> {
+    let mut output = Block::new(sensitivity, Default::default());
+    let mut last = None;
+    while words.len() > 0 {
+        let (new_words, word) = makefile_token(words).expect("tokens should never fail");
+        words = new_words;
+        if word.len() > 0 {
+            last = Some(word);
+        }
+    }
+    if let Some(word) = last {
+        Arc::make_mut(&mut output).push_all_contents(word);
+    }
+    Arc::make_mut(&mut output).simplify();
+
+    output
+}
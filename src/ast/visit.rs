@@ -67,6 +67,16 @@ macro_rules! make_ast_visitor {
                 self.super_words(words);
             }
 
+            /// Visit a `Dir` node
+            fn visit_dir(&mut self, names: &'node $($mutability)? AstNode) {
+                self.super_dir(names);
+            }
+
+            /// Visit a `LastWord` node
+            fn visit_last_word(&mut self, words: &'node $($mutability)? AstNode) {
+                self.super_last_word(words);
+            }
+
             /// Recursion implementation for `Empty` nodes
             fn super_empty(&mut self) {}
 
@@ -113,6 +123,16 @@ macro_rules! make_ast_visitor {
                 self.visit_ast(content);
             }
 
+            /// Recursion implementation for `Dir` nodes
+            fn super_dir(&mut self, names: &'node $($mutability)? AstNode) {
+                self.visit_ast(names);
+            }
+
+            /// Recursion implementation for `LastWord` nodes
+            fn super_last_word(&mut self, words: &'node $($mutability)? AstNode) {
+                self.visit_ast(words);
+            }
+
             /// Core recursion on AST nodes
             fn super_ast(&mut self, ast: &'node $($mutability)? AstNode) {
                 macro_rules! children {
@@ -147,6 +167,12 @@ macro_rules! make_ast_visitor {
                     AstChildren::Words(child) => {
                         self.visit_words(child);
                     }
+                    AstChildren::Dir(child) => {
+                        self.visit_dir(child);
+                    }
+                    AstChildren::LastWord(child) => {
+                        self.visit_last_word(child);
+                    }
                 }
             }
         }
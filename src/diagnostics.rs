@@ -0,0 +1,61 @@
+//! Structured diagnostics produced while reading makefiles.
+//! These correspond to the warnings GNU make prints to stderr, but carry enough
+//! information for tools to act on them programmatically.
+
+use crate::source_location::Location;
+use crate::{FileName, Origin, Rule, VariableName};
+
+/// Something suspicious (but not fatal) that happened while processing a makefile
+#[derive(Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// A rule with a recipe was defined for a target that already had a
+    /// recipe. GNU make reports this as "overriding recipe for target" at the
+    /// new location and "ignoring old recipe for target" at the old one.
+    OverridingRecipe {
+        /// The target whose recipe was replaced
+        target: FileName,
+        /// Where the new (winning) rule was defined
+        new_location: Location,
+        /// Where the old (ignored) rule was defined
+        old_location: Location,
+        /// The rule that was replaced, kept around for inspection
+        old_rule: Box<Rule>,
+    },
+    /// A target was given both ordinary (`:`) and double-colon (`::`) rules.
+    /// GNU make stops with "target file has both : and :: entries"; here the
+    /// new rule is left out.
+    MixedRuleTypes {
+        /// The target of both rules
+        target: FileName,
+        /// Where the rule that was left out was defined
+        new_location: Location,
+        /// Where the earlier rule was defined
+        old_location: Location,
+    },
+    /// An assignment was ignored because the variable already had a value
+    /// with a higher priority origin, such as one from the command line.
+    /// GNU make does this silently.
+    IgnoredAssignment {
+        /// The variable that was assigned
+        variable: VariableName,
+        /// The target the assignment was specific to, if any
+        target: Option<FileName>,
+        /// Where the ignored assignment was made
+        location: Location,
+        /// The origin of the assignment
+        origin: Origin,
+        /// The origin of the value that was kept
+        existing_origin: Origin,
+    },
+}
+
+impl Diagnostic {
+    /// Get the primary location this diagnostic refers to
+    pub fn location(&self) -> &Location {
+        match self {
+            Diagnostic::OverridingRecipe { new_location, .. } => new_location,
+            Diagnostic::MixedRuleTypes { new_location, .. } => new_location,
+            Diagnostic::IgnoredAssignment { location, .. } => location,
+        }
+    }
+}
@@ -1,7 +1,9 @@
 //! Utilities for evaluating makefile expressions
 
 use crate::ast::AstNode;
-use crate::{Database, Engine, NameCache};
+use crate::source_location::Location;
+use crate::types::Set;
+use crate::{Database, Engine, NameCache, VariableName};
 
 #[cfg(test)]
 mod test;
@@ -33,18 +35,45 @@ pub enum Origin {
     EnvironmentOverride,
     /// The user issued a command to set this variable
     Command,
+    /// The variable was set by a makefile using the `override` directive
+    Override,
     /// An automatically generated variable
     Automatic,
     /// Make says that these are "core dump time", may not actually be needed in Hastur
     Invalid,
 }
 
+impl Origin {
+    /// How strongly a value with this origin holds on to its variable. An
+    /// assignment is ignored if the variable already has a value from an
+    /// origin with a higher priority. See #REQ-Variable.priority
+    pub fn priority(self) -> u8 {
+        match self {
+            Origin::Invalid => 0,
+            Origin::Default => 1,
+            Origin::Environment => 2,
+            Origin::File => 3,
+            Origin::EnvironmentOverride => 4,
+            Origin::Command => 5,
+            Origin::Override => 6,
+            Origin::Automatic => 7,
+        }
+    }
+}
+
 /// Everything we need to know to expand a variable
 #[derive(Clone, Debug, PartialEq)]
 pub struct VariableParameters {
     pub(crate) unexpanded_value: AstNode,
     pub(crate) flavor: Flavor,
     pub(crate) origin: Origin,
+    /// Private variables aren't inherited: private globals are hidden from
+    /// recipes, and private target-specific variables from prerequisites.
+    /// See #REQ-Variable.private
+    pub(crate) private: bool,
+    /// Variables that decided whether the assignment was interpreted at all,
+    /// such as those tested by enclosing conditionals. See #SPC-Sensitivity
+    pub(crate) global_sensitivity: Set<VariableName>,
 }
 
 impl VariableParameters {
@@ -53,10 +82,37 @@ impl VariableParameters {
             unexpanded_value,
             flavor,
             origin,
+            private: false,
+            global_sensitivity: Default::default(),
         }
     }
 }
 
+/// Left behind when a variable is removed with `undefine`, so that tools can
+/// explain why the variable no longer has a value
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tombstone {
+    location: Location,
+    origin: Origin,
+}
+
+impl Tombstone {
+    pub(crate) fn new(location: Location, origin: Origin) -> Self {
+        Self { location, origin }
+    }
+
+    /// Get where the variable was undefined
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    /// Get the origin of the `undefine` (`Origin::Override` if it used the
+    /// `override` directive)
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+}
+
 /// Represents a view into a make variable.
 /// These provide some convenience functions for expansion, but don't keep them
 /// around since they tie up exclusive access to the database.
@@ -102,4 +158,25 @@ impl<'d> Variable<'d> {
     pub fn ast(&self) -> &AstNode {
         &self.value.unexpanded_value
     }
+
+    /// Get where this variable's value came from
+    pub fn origin(&self) -> Origin {
+        self.value.origin
+    }
+
+    /// Get the flavor of this variable
+    pub fn flavor(&self) -> Flavor {
+        self.value.flavor
+    }
+
+    /// Check if this variable was declared `private`
+    pub fn is_private(&self) -> bool {
+        self.value.private
+    }
+
+    /// Iterate over the variables that decided whether the assignments to
+    /// this variable were interpreted at all
+    pub fn global_sensitivity(&self) -> impl Iterator<Item = &VariableName> {
+        self.value.global_sensitivity.iter()
+    }
 }
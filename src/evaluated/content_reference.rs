@@ -62,6 +62,11 @@ impl ContentReference {
         }
     }
 
+    /// Get the node backing this reference
+    pub fn node(&self) -> &EvaluatedNode {
+        &self.node
+    }
+
     /// Append the contents of this reference to a string
     pub fn append_to_string(&self, buffer: &mut String) {
         buffer.reserve(self.length);
@@ -184,7 +189,7 @@ impl<'a> Iterator for SegmentsIter<'a> {
         }
 
         let mut tr = self.internal.next()?;
-        while tr.len() < self.offset {
+        while tr.len() <= self.offset {
             self.offset -= tr.len();
             tr = self.internal.next()?;
         }
@@ -189,3 +189,10 @@ pub fn substitution_reference(
         nodes::SubstitutionReference::new(name, key, replacement, value),
     )))
 }
+
+/// Create a content reference to the result of a second expansion
+pub fn second_expansion(first_expansion: Arc<Block>, value: Arc<Block>) -> ContentReference {
+    ContentReference::new_from_node(Arc::new(EvaluatedNode::SecondExpansion(
+        nodes::SecondExpansion::new(first_expansion, value),
+    )))
+}
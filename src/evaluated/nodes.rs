@@ -19,6 +19,9 @@ pub enum EvaluatedNode {
     SubstitutionReference(Box<SubstitutionReference>),
     /// A reference to content produced by an eval function call
     Evaluated(Box<Evaluated>),
+    /// Content produced by expanding the result of an earlier expansion a
+    /// second time, as happens to prerequisites after `.SECONDEXPANSION`
+    SecondExpansion(Box<SecondExpansion>),
 }
 
 lazy_static::lazy_static!(
@@ -57,6 +60,7 @@ impl EvaluatedNode {
             EvaluatedNode::VariableReference(v) => v.value.len(),
             EvaluatedNode::SubstitutionReference(v) => v.value.len(),
             EvaluatedNode::Evaluated(v) => v.value.len(),
+            EvaluatedNode::SecondExpansion(v) => v.value.len(),
         }
     }
 
@@ -72,6 +76,9 @@ impl EvaluatedNode {
                 Chars(CharsInternal::BlockSpan(val.value.span().chars()))
             }
             EvaluatedNode::Evaluated(v) => Chars(CharsInternal::BlockSpan(v.value.span().chars())),
+            EvaluatedNode::SecondExpansion(v) => {
+                Chars(CharsInternal::BlockSpan(v.value.span().chars()))
+            }
         }
     }
 
@@ -87,6 +94,9 @@ impl EvaluatedNode {
                 SegmentsInternal::BlockSpan(v.value.span().segments())
             }
             EvaluatedNode::Evaluated(v) => SegmentsInternal::BlockSpan(v.value.span().segments()),
+            EvaluatedNode::SecondExpansion(v) => {
+                SegmentsInternal::BlockSpan(v.value.span().segments())
+            }
         })
     }
 }
@@ -236,3 +246,31 @@ impl Evaluated {
         &self.value
     }
 }
+
+/// Content produced by a second expansion pass
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecondExpansion {
+    first_expansion: Arc<Block>,
+    value: Arc<Block>,
+}
+
+impl SecondExpansion {
+    /// Create a new second expansion node
+    pub fn new(first_expansion: Arc<Block>, value: Arc<Block>) -> Box<Self> {
+        Box::new(Self {
+            first_expansion,
+            value,
+        })
+    }
+
+    /// Get the text produced by the first expansion, which was then expanded
+    /// again to produce this node's value
+    pub fn first_expansion(&self) -> &Block {
+        &self.first_expansion
+    }
+
+    /// Get the value produced by the second expansion
+    pub fn value(&self) -> &Block {
+        &self.value
+    }
+}
@@ -0,0 +1,415 @@
+//! Access to the file system, abstracted so that makefiles can be read from
+//! somewhere other than the disk.
+//!
+//! Everything the engine learns about files (included makefiles, wildcard
+//! expansions, existence checks and modification times) goes through the
+//! [`FileSystem`](trait.FileSystem.html) installed in
+//! [`Engine::filesystem`](../struct.Engine.html#structfield.filesystem).
+//! [`OsFileSystem`](struct.OsFileSystem.html) is the default;
+//! [`MemoryFileSystem`](struct.MemoryFileSystem.html) lets editors parse
+//! unsaved buffers and lets tests describe a source tree inline.
+use crate::clean_path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The file system operations the engine needs. Implementations must be
+/// thread safe, so that engines can be handed to other threads.
+pub trait FileSystem: Send + Sync {
+    /// Read the whole contents of a file
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+
+    /// Check whether the path names a regular file
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Check whether the path names a directory
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Get the last modification time of a file
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// Get the names of the entries of a directory, in no particular order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>>;
+
+    /// Check whether anything exists at the path
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+}
+
+/// The real file system, accessed through `std::fs`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        std::fs::metadata(path)?.modified()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MemoryFile {
+    contents: String,
+    modified: SystemTime,
+}
+
+/// A file system that only contains the files it has been given.
+/// Directories exist implicitly whenever they contain a file.
+///
+/// Paths are looked up exactly as the engine builds them (after removing `.`
+/// components), so files should usually be added with absolute paths under
+/// the engine's `working_directory`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    files: BTreeMap<PathBuf, MemoryFile>,
+}
+
+impl MemoryFileSystem {
+    /// Create an empty file system
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a file, replacing any file already at that path. The file's
+    /// modification time is the Unix epoch until
+    /// [`set_modified`](#method.set_modified) is called.
+    pub fn add_file<P: AsRef<Path>, S: Into<String>>(&mut self, path: P, contents: S) {
+        self.files.insert(
+            clean_path(path.as_ref()),
+            MemoryFile {
+                contents: contents.into(),
+                modified: SystemTime::UNIX_EPOCH,
+            },
+        );
+    }
+
+    /// Remove a file, returning its contents if it existed
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Option<String> {
+        self.files
+            .remove(&clean_path(path.as_ref()))
+            .map(|file| file.contents)
+    }
+
+    /// Set the modification time of a file. Returns false if there is no file
+    /// at that path.
+    pub fn set_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> bool {
+        match self.files.get_mut(&clean_path(path.as_ref())) {
+            Some(file) => {
+                file.modified = modified;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<&MemoryFile> {
+        self.files
+            .get(&clean_path(path))
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        self.file(path).map(|file| file.contents.clone())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.file(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = clean_path(path);
+        self.files
+            .keys()
+            .any(|file| file != &path && file.starts_with(&path))
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        if self.is_dir(path) {
+            return Ok(SystemTime::UNIX_EPOCH);
+        }
+        self.file(path).map(|file| file.modified)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        let path = clean_path(path);
+        let entries: BTreeSet<String> = self
+            .files
+            .keys()
+            .filter_map(|file| file.strip_prefix(&path).ok())
+            .filter_map(|relative| relative.components().next())
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if entries.is_empty() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// A [`MemoryFileSystem`](struct.MemoryFileSystem.html) layered over another
+/// file system. Files in the upper layer hide files at the same path in the
+/// lower one, e.g. unsaved editor buffers over the disk, or generated
+/// makefiles supplied when [restarting](../struct.Engine.html#method.restart).
+#[derive(Clone)]
+pub struct OverlayFileSystem {
+    upper: MemoryFileSystem,
+    lower: Arc<dyn FileSystem>,
+}
+
+impl OverlayFileSystem {
+    /// Layer `upper` over `lower`
+    pub fn new(upper: MemoryFileSystem, lower: Arc<dyn FileSystem>) -> Self {
+        OverlayFileSystem { upper, lower }
+    }
+}
+
+impl FileSystem for OverlayFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        if self.upper.is_file(path) {
+            return self.upper.read_to_string(path);
+        }
+        self.lower.read_to_string(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.upper.is_file(path) || self.lower.is_file(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.upper.is_dir(path) || self.lower.is_dir(path)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        if self.upper.is_file(path) {
+            return self.upper.modified(path);
+        }
+        self.lower.modified(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        let upper = self.upper.read_dir(path);
+        let lower = self.lower.read_dir(path);
+        if upper.is_err() {
+            return lower;
+        }
+        let mut entries: BTreeSet<String> = upper?.into_iter().collect();
+        entries.extend(lower.unwrap_or_default());
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// Check whether a name contains any of the glob metacharacters
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Match a single path component against a shell glob (`*`, `?`, `[...]` and
+/// backslash escapes), like `fnmatch(3)`
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some(('[', rest)) => match (name.split_first(), match_bracket(rest)) {
+            (Some((c, name_rest)), Some((set, negated, rest))) => {
+                let in_set = set.iter().any(|&(low, high)| low <= *c && *c <= high);
+                in_set != negated && glob_match(rest, name_rest)
+            }
+            // An unterminated bracket matches itself
+            (Some(('[', name_rest)), None) => glob_match(rest, name_rest),
+            _ => false,
+        },
+        Some(('\\', rest)) if !rest.is_empty() => {
+            name.first() == rest.first() && glob_match(&rest[1..], &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// Parse the body of a `[...]` expression (the opening bracket has already
+/// been consumed). Returns the character ranges, whether the set is negated,
+/// and the rest of the pattern.
+#[allow(clippy::type_complexity)]
+fn match_bracket(pattern: &[char]) -> Option<(Vec<(char, char)>, bool, &[char])> {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some(('!', rest)) | Some(('^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let (low, rest) = match pattern.split_first() {
+            None => return None,
+            Some((']', rest)) if !first => return Some((ranges, negated, rest)),
+            Some(('\\', rest)) if !rest.is_empty() => (rest[0], &rest[1..]),
+            Some((c, rest)) => (*c, rest),
+        };
+        first = false;
+        pattern = rest;
+        match pattern {
+            ['-', high, rest @ ..] if *high != ']' => {
+                ranges.push((low, *high));
+                pattern = rest;
+            }
+            _ => ranges.push((low, low)),
+        }
+    }
+}
+
+/// Expand a shell glob the way `$(wildcard)` does. Relative patterns are
+/// resolved against `directory` but the names returned stay relative. Names
+/// without glob characters are returned only if they exist. The results are
+/// sorted.
+pub(crate) fn wildcard(
+    filesystem: &dyn FileSystem,
+    directory: &Path,
+    pattern: &str,
+) -> Vec<String> {
+    // Each candidate is the name as it will be reported, and its real path
+    let mut candidates = if pattern.starts_with('/') {
+        vec![(String::from("/"), PathBuf::from("/"))]
+    } else {
+        vec![(String::new(), directory.to_path_buf())]
+    };
+
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        if !is_glob(component) {
+            for (name, path) in candidates.iter_mut() {
+                if !name.is_empty() && !name.ends_with('/') {
+                    name.push('/');
+                }
+                name.push_str(component);
+                path.push(component);
+            }
+            continue;
+        }
+
+        let component_chars: Vec<char> = component.chars().collect();
+        let mut next = Vec::new();
+        for (name, path) in candidates {
+            let mut entries = match filesystem.read_dir(&path) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            entries.sort();
+            for entry in entries {
+                // Like the shell, hidden files need to be matched explicitly
+                if entry.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                let entry_chars: Vec<char> = entry.chars().collect();
+                if glob_match(&component_chars, &entry_chars) {
+                    let mut name = name.clone();
+                    if !name.is_empty() && !name.ends_with('/') {
+                        name.push('/');
+                    }
+                    name.push_str(&entry);
+                    next.push((name, path.join(&entry)));
+                }
+            }
+        }
+        candidates = next;
+    }
+
+    if pattern.ends_with('/') {
+        for (name, _) in candidates.iter_mut() {
+            name.push('/');
+        }
+    }
+
+    let mut found: Vec<String> = candidates
+        .into_iter()
+        .filter(|(_, path)| filesystem.exists(path))
+        .map(|(name, _)| name)
+        .collect();
+    found.sort();
+    found.dedup();
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        glob_match(&pattern, &name)
+    }
+
+    #[test]
+    fn engines_are_thread_safe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<crate::Engine>();
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(matches("*.c", "main.c"));
+        assert!(matches("*.c", ".c"));
+        assert!(!matches("*.c", "main.h"));
+        assert!(matches("m?in.c", "main.c"));
+        assert!(!matches("m?in.c", "mn.c"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("[abc", "[abc"));
+    }
+
+    #[test]
+    fn memory_wildcard() {
+        let mut filesystem = MemoryFileSystem::new();
+        filesystem.add_file("/src/main.c", "");
+        filesystem.add_file("/src/util.c", "");
+        filesystem.add_file("/src/util.h", "");
+        filesystem.add_file("/src/.hidden.c", "");
+        filesystem.add_file("/src/lib/extra.c", "");
+        let directory = Path::new("/src");
+
+        assert_eq!(
+            wildcard(&filesystem, directory, "*.c"),
+            vec!["main.c", "util.c"]
+        );
+        assert_eq!(
+            wildcard(&filesystem, directory, "*/*.c"),
+            vec!["lib/extra.c"]
+        );
+        assert_eq!(
+            wildcard(&filesystem, directory, "/src/*.h"),
+            vec!["/src/util.h"]
+        );
+        assert_eq!(wildcard(&filesystem, directory, ".*.c"), vec![".hidden.c"]);
+        assert_eq!(wildcard(&filesystem, directory, "main.c"), vec!["main.c"]);
+        assert!(wildcard(&filesystem, directory, "missing.c").is_empty());
+        assert!(wildcard(&filesystem, directory, "*.o").is_empty());
+    }
+}
@@ -34,24 +34,36 @@ extern crate nom;
 mod test_macros;
 
 pub mod ast;
+pub mod diagnostics;
 mod eval;
 pub mod evaluated;
+pub mod filesystem;
 mod parsers;
 pub mod pattern;
+pub mod remake;
+pub mod skipped_branch;
 pub mod source_location;
+pub mod special_targets;
 pub mod traits;
 mod types;
 
-pub use crate::eval::{Flavor, Origin, Variable, VariableParameters};
+pub use crate::diagnostics::Diagnostic;
+pub use crate::eval::{Flavor, Origin, Tombstone, Variable, VariableParameters};
+pub use crate::filesystem::{FileSystem, MemoryFileSystem, OsFileSystem, OverlayFileSystem};
 pub use crate::parsers::ParserCompliance;
+pub use crate::remake::{MissingInclude, RemakeAction, RemakePlan};
+pub use crate::skipped_branch::{SkipReason, SkippedBranch};
+pub use crate::special_targets::{GlobalFlags, TargetAttributes};
 
 use crate::ast::AstNode;
 use crate::evaluated::Block;
 use crate::source_location::{LocatedString, Location};
+use std::collections::BTreeMap;
 use std::io;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 use string_interner::DefaultStringInterner as StringInterner;
 use string_interner::Sym;
 
@@ -90,6 +102,13 @@ pub enum ParseErrorKind {
     /// We expected a conditional but found something else
     ConditionalExpected,
 
+    /// We expected a directive (`include`, `export`, ...) but found something else
+    DirectiveExpected,
+
+    /// A static pattern rule (`targets: target-pattern: prereq-patterns`) was
+    /// found. These aren't supported yet.
+    StaticPatternRule,
+
     /// An ifeq/ifneq was encountered but had an illegal separator character
     BadIfEqSeparator,
 
@@ -117,6 +136,14 @@ pub enum ParseErrorKind {
     /// is a pain
     IncludeFailure(std::io::ErrorKind, String),
 
+    /// A `define` was still open when the makefile ended. The location is
+    /// that of the unterminated `define` line
+    UnterminatedDefine(source_location::Location),
+
+    /// A `define` was used as a target-specific variable assignment, as in
+    /// `all: define FOO`. Like GNU make, we don't allow this.
+    TargetSpecificDefine,
+
     /// This variant should never surface in library consumer code in practice,
     /// but we need it to make nom types work out
     NomError(u32),
@@ -143,6 +170,108 @@ pub struct Command {
     unexpanded_command: AstNode,
 }
 
+impl Command {
+    /// Get the command line as it was written, with the recipe prefix removed
+    pub fn unexpanded(&self) -> &AstNode {
+        &self.unexpanded_command
+    }
+}
+
+/// The directories GNU make searches for included makefiles after the ones
+/// given with `-I`, if they exist
+const DEFAULT_INCLUDE_DIRECTORIES: &[&str] =
+    &["/usr/gnu/include", "/usr/local/include", "/usr/include"];
+
+/// The version of GNU make whose behavior we follow, reported in
+/// `MAKE_VERSION`
+const MAKE_VERSION: &str = "4.2.1";
+
+/// The optional features of GNU make we support, reported in `.FEATURES`
+const FEATURES: &str = "target-specific second-expansion else-if undefine oneshell";
+
+/// Remove the `.` components of a path. `..` components are left alone,
+/// since removing them would change the meaning of paths through symlinks.
+pub(crate) fn clean_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != std::path::Component::CurDir)
+        .collect()
+}
+
+/// Split a search path (the value of `VPATH`, or the directories given to a
+/// `vpath` directive) into directories. Like GNU make, directories are
+/// separated by whitespace or `:` (`;` on Windows), and trailing slashes are
+/// removed.
+pub(crate) fn split_search_path(search_path: &str) -> Vec<PathBuf> {
+    let separator = if cfg!(windows) { ';' } else { ':' };
+    search_path
+        .split(|c: char| c == separator || c.is_whitespace())
+        .filter(|directory| !directory.is_empty())
+        .map(|directory| match directory.trim_end_matches('/') {
+            "" => PathBuf::from("/"),
+            trimmed => PathBuf::from(trimmed),
+        })
+        .collect()
+}
+
+/// A makefile that was read because of an `include` directive
+#[derive(Clone, Debug, PartialEq)]
+pub struct Include {
+    /// The name given to the `include` directive
+    pub name: String,
+    /// The path the makefile was read from
+    pub path: PathBuf,
+    /// The search directory the makefile was found in, or `None` if it was
+    /// found without searching (the name was absolute, or relative to the
+    /// working directory)
+    pub directory: Option<PathBuf>,
+    /// The location of the `include` directive
+    pub location: Location,
+}
+
+/// A script produced by expanding a recipe, ready to be handed to the shell.
+/// See [`Engine::expand_recipe`](struct.Engine.html#method.expand_recipe)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpandedCommand {
+    /// The text of the script, without its prefix characters
+    pub script: String,
+    /// The script was prefixed with `@`, so it shouldn't be echoed
+    pub silent: bool,
+    /// The script was prefixed with `-`, so failures should be ignored
+    pub ignore_errors: bool,
+    /// The script was prefixed with `+`, so it runs even under `make -n`
+    pub always_run: bool,
+}
+
+impl ExpandedCommand {
+    /// Create a command from an expanded line, interpreting any leading
+    /// `@`, `-` and `+` characters
+    fn from_line(line: &str) -> Self {
+        let mut tr = ExpandedCommand {
+            script: String::new(),
+            silent: false,
+            ignore_errors: false,
+            always_run: false,
+        };
+        let script = line.trim_start_matches(|c: char| match c {
+            '@' => {
+                tr.silent = true;
+                true
+            }
+            '-' => {
+                tr.ignore_errors = true;
+                true
+            }
+            '+' => {
+                tr.always_run = true;
+                true
+            }
+            c => c.is_whitespace(),
+        });
+        tr.script = script.into();
+        tr
+    }
+}
+
 /// Represents what type of rule this is (standard, double colon, static pattern, etc.)
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RuleType {
@@ -163,6 +292,30 @@ impl Recipe {
     }
 }
 
+/// Prerequisites of a rule declared after `.SECONDEXPANSION`. These survive
+/// the first expansion (performed while reading the makefile) as text, and
+/// are only turned into dependencies once they are expanded again with the
+/// automatic variables of a specific target bound.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecondaryPrerequisites {
+    /// The text produced by the first expansion
+    first_expansion: Arc<Block>,
+    /// The AST parsed out of `first_expansion`
+    unexpanded: AstNode,
+}
+
+impl SecondaryPrerequisites {
+    /// Get the text produced by the first expansion
+    pub fn first_expansion(&self) -> &Arc<Block> {
+        &self.first_expansion
+    }
+
+    /// Get the AST that will be evaluated during the second expansion
+    pub fn unexpanded(&self) -> &AstNode {
+        &self.unexpanded
+    }
+}
+
 /// A "rule" describes how to bring a set of "targets" up to date from a set of
 /// "dependencies", using a "recipe".
 #[derive(Clone, Debug, PartialEq)]
@@ -175,10 +328,15 @@ pub struct Rule {
     deps: Vec<Arc<Block>>,
     /// File names, cached as interned strings
     dep_names: fxhash::FxHashSet<FileName>,
+    /// Prerequisites still awaiting their second expansion, in declaration order
+    secondary_deps: Vec<SecondaryPrerequisites>,
     /// The recipe to turn `deps` into `targets`
     recipe: Arc<Recipe>,
     /// Extra information about the rule
     rule_type: RuleType,
+    /// Variables that decided whether the rule (or any line of its recipe)
+    /// was interpreted at all. See #SPC-Sensitivity
+    global_sensitivity: types::Set<VariableName>,
 }
 
 // TODO: move this impl into rule.rs
@@ -202,6 +360,24 @@ impl Rule {
     pub fn dependencies(&self) -> &[Arc<Block>] {
         &self.deps
     }
+
+    /// Get the prerequisites that have not yet been through
+    /// [`Engine::expand_secondary_prerequisites`](struct.Engine.html#method.expand_secondary_prerequisites)
+    pub fn secondary_dependencies(&self) -> &[SecondaryPrerequisites] {
+        &self.secondary_deps
+    }
+
+    /// Get the location at which the target of this rule was named
+    pub fn location(&self) -> Location {
+        self.target.span().location().unwrap_or(Location::Synthetic)
+    }
+
+    /// Iterate over the variables that decided whether this rule, or any
+    /// line of its recipe, was interpreted at all. For example, a rule
+    /// written inside an `ifdef FOO` is globally sensitive to `FOO`.
+    pub fn global_sensitivity(&self) -> impl Iterator<Item = &VariableName> {
+        self.global_sensitivity.iter()
+    }
 }
 
 /// Type representing an opaque variable name reference.
@@ -225,24 +401,71 @@ pub struct Database {
     target_variables: types::Map<FileName, types::Map<VariableName, VariableParameters>>,
     /// All known rules
     rules: types::Map<FileName, Rule>,
+    /// When the first rule for each target of `rules` was defined, counting
+    /// from zero
+    rule_order: types::Map<FileName, usize>,
+    /// Attributes given to targets by special targets such as `.PHONY`
+    target_attributes: types::Map<FileName, TargetAttributes>,
+    /// Flags set by special targets that apply to everything
+    flags: GlobalFlags,
+    /// Global variables explicitly marked with `export` (`true`) or
+    /// `unexport` (`false`)
+    exports: types::Map<VariableName, bool>,
+    /// Target-specific variables explicitly marked with `export` or `unexport`
+    target_exports: types::Map<FileName, types::Map<VariableName, bool>>,
+    /// Global variables removed with `undefine`
+    tombstones: types::Map<VariableName, Tombstone>,
+    /// Target-specific variables removed with `undefine`
+    target_tombstones: types::Map<FileName, types::Map<VariableName, Tombstone>>,
+    /// Variables that decide which parts of the makefile are interpreted at
+    /// all, such as the names tested by `ifdef`
+    global_sensitivity: types::Set<VariableName>,
+    /// The target `make` builds when no goals are given: the expanded value
+    /// of `.DEFAULT_GOAL`, kept up to date as it changes
+    default_goal: Option<FileName>,
+    /// Every double-colon rule for each target, in the order they were
+    /// defined. The last one is also in `rules`.
+    double_colon_rules: types::Map<FileName, Vec<Rule>>,
+    /// Conditional branches that weren't interpreted, keyed by the order
+    /// they were read
+    skipped_branches: types::Map<usize, SkippedBranch>,
 }
 
 impl Database {
-    /// Iterate over all the rules in this database
+    /// Iterate over all the rules in this database, in the order their
+    /// targets were first defined
     pub fn rules(&self) -> impl Iterator<Item=&Rule> {
-        self.rules.values()
+        let mut rules: Vec<&Rule> = self.rules.values().collect();
+        rules.sort_by_key(|rule| self.rule_order[&rule.target_fname]);
+        rules.into_iter()
     }
 
     /// Add a rule into the database
     pub fn add_rule(&self, rule: Rule) -> Self {
         let mut tr = self.clone();
+        tr.record_rule_order(rule.target_fname);
         tr.rules.insert(rule.target_fname, rule);
         tr
     }
 
-    /// Create a rule from the a protorule
-    fn from_protorule(&self, names: &mut NameCache, rule: crate::parsers::ProtoRule) -> Self {
+    /// Remember when the first rule for a target was defined
+    fn record_rule_order(&mut self, target: FileName) {
+        if !self.rule_order.contains_key(&target) {
+            let order = self.rule_order.len();
+            self.rule_order.insert(target, order);
+        }
+    }
+
+    /// Create a rule from the a protorule.
+    /// Any diagnostics generated while merging the new rule into the database
+    /// are returned alongside the updated database.
+    fn from_protorule(
+        &self,
+        names: &mut NameCache,
+        rule: crate::parsers::ProtoRule,
+    ) -> (Self, Vec<Diagnostic>) {
         let mut tr = self.clone();
+        let mut diagnostics = Vec::new();
         let has_no_commands = rule.recipe.0.len() == 0;
         let recipe = Arc::new(rule.recipe);
         let deps = &rule.deps;
@@ -252,64 +475,137 @@ impl Database {
             .map(|dep| names.intern_file_name(dep.into_string()))
             .collect();
         let deps_as_fnames = &deps_as_fnames;
+        let secondary_deps = &rule.secondary_deps;
         let rule_type = rule.rule_type;
+        let global_sensitivity = &rule.global_sensitivity;
+
+        // Special targets don't describe files, they change how their
+        // prerequisites (or everything) are treated
+        let mut targets = Vec::with_capacity(rule.targets.len());
+        for target in rule.targets.into_iter() {
+            match special_targets::SpecialTarget::from_name(&target.into_string()) {
+                Some(special) => {
+                    debug!("Applying special target {:?}", special);
+                    special.apply(
+                        &mut tr.target_attributes,
+                        &mut tr.flags,
+                        deps_as_fnames.iter().cloned(),
+                    );
+                }
+                None => targets.push(target),
+            }
+        }
 
-        // Empty recipes are special: these rules may append to the dep list, while
-        // other rules may not (and instead must overwrite the previous rule)
-        if has_no_commands {
-            debug!("protorule for targets {:?} had no commands", rule.targets);
-            for target in rule.targets.into_iter() {
-                let target_as_fname = names.intern_file_name(target.into_string());
-                tr.rules
-                    .entry(target_as_fname)
-                    .and_modify(|existing_rule| {
-                        for dep in deps {
-                            existing_rule.deps.push(Arc::clone(dep));
-                        }
-                        for dep in deps_as_fnames {
-                            existing_rule.dep_names.insert(*dep);
-                        }
-                    })
-                    .or_insert_with(|| Rule {
-                        target,
-                        target_fname: target_as_fname,
-                        deps: deps.iter().map(|x| Arc::clone(x)).collect(),
-                        dep_names: deps_as_fnames.clone(),
-                        recipe: Arc::clone(&recipe),
-                        rule_type,
+        // Like GNU make, the first ordinary target becomes the default goal
+        // unless `.DEFAULT_GOAL` already expands to something
+        if tr.default_goal.is_none() {
+            if let Some(goal) = targets
+                .iter()
+                .find(|target| is_default_goal_candidate(&target.into_string()))
+            {
+                debug!("Default goal is now {:?}", goal);
+                tr.default_goal = Some(names.intern_file_name(goal.into_string()));
+                let default_goal = names.intern_variable_name(".DEFAULT_GOAL".into());
+                tr = tr.set_variable(
+                    default_goal,
+                    VariableParameters::new(
+                        ast::preevaluated(Location::Synthetic, Arc::clone(goal)),
+                        Flavor::Simple,
+                        Origin::File,
+                    ),
+                );
+            }
+        }
+
+        use im::hashmap::Entry;
+        for target in targets.into_iter() {
+            let target_as_fname = names.intern_file_name(target.into_string());
+            let new_rule = Rule {
+                target,
+                target_fname: target_as_fname,
+                deps: deps.iter().map(|x| Arc::clone(x)).collect(),
+                dep_names: deps_as_fnames.clone(),
+                secondary_deps: secondary_deps.clone(),
+                recipe: Arc::clone(&recipe),
+                rule_type,
+                global_sensitivity: global_sensitivity.clone(),
+            };
+
+            // GNU make stops when a target has both `:` and `::` rules; the
+            // new rule is left out
+            if let Some(old_rule) = tr.rules.get(&target_as_fname) {
+                if old_rule.rule_type != rule_type {
+                    warn!("Target {:?} has both : and :: rules", target_as_fname);
+                    diagnostics.push(Diagnostic::MixedRuleTypes {
+                        target: target_as_fname,
+                        new_location: new_rule.location(),
+                        old_location: old_rule.location(),
                     });
+                    continue;
+                }
             }
-        } else {
-            use im::hashmap::Entry;
-            debug!(
-                "protorule for targets {:?} had commands {:?}",
-                rule.targets, recipe
-            );
 
-            for target in rule.targets.into_iter() {
-                let target_as_fname = names.intern_file_name(target.into_string());
-                let new_rule = Rule {
-                    target,
-                    target_fname: target_as_fname,
-                    deps: rule.deps.iter().map(|x| Arc::clone(x)).collect(),
-                    dep_names: deps_as_fnames.clone(),
-                    recipe: Arc::clone(&recipe),
-                    rule_type: rule.rule_type,
-                };
-                match tr.rules.entry(target_as_fname) {
-                    Entry::Occupied(mut o) => {
-                        // TODO: bubble this up in a structured manner instead of just printing nonsense
-                        warn!("Overwriting existing rule for target {:?}", target_as_fname);
-                        o.insert(new_rule);
-                    }
-                    Entry::Vacant(v) => {
-                        v.insert(new_rule);
+            // Double-colon rules are independent of each other: each keeps
+            // its own prerequisites and recipe
+            if rule_type == RuleType::DoubleColon {
+                tr.record_rule_order(target_as_fname);
+                tr.double_colon_rules
+                    .entry(target_as_fname)
+                    .or_default()
+                    .push(new_rule.clone());
+                tr.rules.insert(target_as_fname, new_rule);
+                continue;
+            }
+
+            tr.record_rule_order(target_as_fname);
+            match tr.rules.entry(target_as_fname) {
+                // Empty recipes are special: these rules only append to the
+                // dep list, while other rules also replace the recipe
+                Entry::Occupied(mut o) if has_no_commands => {
+                    debug!("protorule for {:?} had no commands", target_as_fname);
+                    let existing_rule = o.get_mut();
+                    existing_rule.deps.extend(new_rule.deps);
+                    existing_rule.dep_names.extend(new_rule.dep_names);
+                    existing_rule.secondary_deps.extend(new_rule.secondary_deps);
+                    existing_rule
+                        .global_sensitivity
+                        .extend(new_rule.global_sensitivity);
+                }
+                Entry::Occupied(mut o) => {
+                    let mut new_rule = new_rule;
+                    let old_rule = o.get();
+                    if !old_rule.recipe.0.is_empty() {
+                        warn!("Overriding recipe for target {:?}", target_as_fname);
+                        diagnostics.push(Diagnostic::OverridingRecipe {
+                            target: target_as_fname,
+                            new_location: new_rule.location(),
+                            old_location: old_rule.location(),
+                            old_rule: Box::new(old_rule.clone()),
+                        });
                     }
+                    // The dependencies accumulate (GNU make puts the old
+                    // ones first)
+                    let mut deps = old_rule.deps.clone();
+                    deps.append(&mut new_rule.deps);
+                    new_rule.deps = deps;
+                    new_rule
+                        .dep_names
+                        .extend(old_rule.dep_names.iter().cloned());
+                    let mut secondary_deps = old_rule.secondary_deps.clone();
+                    secondary_deps.append(&mut new_rule.secondary_deps);
+                    new_rule.secondary_deps = secondary_deps;
+                    new_rule
+                        .global_sensitivity
+                        .extend(old_rule.global_sensitivity.iter().cloned());
+                    o.insert(new_rule);
+                }
+                Entry::Vacant(v) => {
+                    v.insert(new_rule);
                 }
             }
         }
 
-        tr
+        (tr, diagnostics)
     }
 
     /// Set the value of a variable
@@ -318,13 +614,15 @@ impl Database {
         info!("Setting variable {:?} to {:?}", name, value);
         let mut tr = self.clone();
         tr.variables.insert(name, value);
+        tr.tombstones.remove(&name);
         tr
     }
 
     /// Append to a variable at the specified location
     /// The provided parameters are all used to update the variable state,
     /// though if the variable was previously defined.
-    /// If `implicit_space` is true, we add a space between any old and new values.
+    /// If `implicit_space` is true, we add a space between any old and new
+    /// values. Like GNU make, no space is added after an empty old value.
     #[inline]
     pub fn append_to_variable(
         &self,
@@ -334,11 +632,16 @@ impl Database {
         implicit_space: bool,
     ) -> Self {
         let mut tr = self.clone();
-        // tr.variables.
+        tr.tombstones.remove(&name);
         tr.variables = tr.variables.update_with(name, content, |mut old, new| {
+            if new.origin.priority() > old.origin.priority() {
+                old.origin = new.origin;
+            }
+            old.private |= new.private;
+            old.global_sensitivity = old.global_sensitivity.union(new.global_sensitivity);
             old.unexpanded_value = ast::collapsing_concat(
                 location,
-                if implicit_space {
+                if implicit_space && !old.unexpanded_value.is_empty() {
                     vec![
                         old.unexpanded_value,
                         ast::constant(LocatedString::new(Location::Synthetic.into(), " ".into())),
@@ -368,9 +671,290 @@ impl Database {
             .entry(target)
             .or_default()
             .insert(name, value);
+        if let Some(tombstones) = tr.target_tombstones.get_mut(&target) {
+            tombstones.remove(&name);
+        }
+        tr
+    }
+
+    /// Append to a variable for a specific target. If the target doesn't
+    /// have its own value yet, the new content is appended to the current
+    /// global value.
+    pub fn append_to_variable_for_target(
+        &self,
+        target: FileName,
+        name: VariableName,
+        location: Location,
+        content: VariableParameters,
+    ) -> Self {
+        let mut tr = self.clone();
+        if let Some(tombstones) = tr.target_tombstones.get_mut(&target) {
+            tombstones.remove(&name);
+        }
+        let variables = tr.target_variables.entry(target).or_default();
+        let old = match variables.get(&name) {
+            Some(old) => Some(old.clone()),
+            // Start from the global value, but keep the new origin and privacy
+            None => self.variables.get(&name).map(|global| VariableParameters {
+                unexpanded_value: global.unexpanded_value.clone(),
+                flavor: global.flavor,
+                global_sensitivity: global.global_sensitivity.clone(),
+                ..content.clone()
+            }),
+        };
+        match old {
+            Some(mut old) => {
+                if content.origin.priority() > old.origin.priority() {
+                    old.origin = content.origin;
+                }
+                old.private |= content.private;
+                old.global_sensitivity = old
+                    .global_sensitivity
+                    .union(content.global_sensitivity.clone());
+                old.unexpanded_value = ast::collapsing_concat(
+                    location,
+                    vec![
+                        old.unexpanded_value,
+                        ast::constant(LocatedString::synthetic_new(" ")),
+                        content.unexpanded_value,
+                    ],
+                );
+                variables.insert(name, old);
+            }
+            None => {
+                variables.insert(name, content);
+            }
+        }
+        tr
+    }
+
+    /// Mark a global variable as exported to (or, if `export` is false,
+    /// hidden from) the environment of recipes
+    pub fn set_variable_export(&self, name: VariableName, export: bool) -> Self {
+        let mut tr = self.clone();
+        tr.exports.insert(name, export);
+        tr
+    }
+
+    /// Mark a target-specific variable as exported to (or hidden from) the
+    /// environment of that target's recipe
+    pub fn set_target_variable_export(
+        &self,
+        target: FileName,
+        name: VariableName,
+        export: bool,
+    ) -> Self {
+        let mut tr = self.clone();
+        tr.target_exports
+            .entry(target)
+            .or_default()
+            .insert(name, export);
         tr
     }
 
+    /// Set whether variables without an explicit `export` or `unexport` are
+    /// exported, as done by `.EXPORT_ALL_VARIABLES` or a bare `export`
+    pub fn set_export_all_variables(&self, export_all: bool) -> Self {
+        let mut tr = self.clone();
+        tr.flags.export_all_variables = export_all;
+        tr
+    }
+
+    /// Check if a variable would be placed in the environment of recipes.
+    /// Following GNU make, variables from the environment or the command line
+    /// are exported unless they have been `unexport`ed, while variables from
+    /// makefiles need an explicit `export` (or `.EXPORT_ALL_VARIABLES`).
+    /// Built-in and automatic variables are only exported by explicit request.
+    pub fn is_exported(&self, name: VariableName) -> bool {
+        match self.variables.get(&name) {
+            Some(value) => self.export_state(self.exports.get(&name).cloned(), value),
+            None => false,
+        }
+    }
+
+    /// Decide whether a variable with the given explicit export state is
+    /// exported
+    fn export_state(&self, explicit: Option<bool>, value: &VariableParameters) -> bool {
+        match explicit {
+            Some(export) => export,
+            None => match value.origin {
+                Origin::Default | Origin::Automatic | Origin::Invalid => false,
+                Origin::Environment | Origin::EnvironmentOverride | Origin::Command => true,
+                Origin::File | Origin::Override => self.flags.export_all_variables,
+            },
+        }
+    }
+
+    /// Check if an assignment from `origin` is allowed to change a global
+    /// variable. If it isn't, the origin of the value that takes priority is
+    /// returned.
+    pub fn blocking_origin(&self, name: VariableName, origin: Origin) -> Option<Origin> {
+        let existing = self.variables.get(&name)?.origin;
+        if existing.priority() > origin.priority() {
+            Some(existing)
+        } else {
+            None
+        }
+    }
+
+    /// Remove a global variable, as done by `undefine`. Values with a higher
+    /// priority than `origin` are kept (so removing a variable set on the
+    /// command line requires `override undefine`). A tombstone recording
+    /// `location` is left behind.
+    pub fn undefine_variable(
+        &self,
+        name: VariableName,
+        location: Location,
+        origin: Origin,
+    ) -> Self {
+        if self.blocking_origin(name, origin).is_some() {
+            return self.clone();
+        }
+        let mut tr = self.clone();
+        tr.variables.remove(&name);
+        tr.tombstones.insert(name, Tombstone::new(location, origin));
+        tr
+    }
+
+    /// Remove a target-specific variable. As with
+    /// [`undefine_variable`](#method.undefine_variable), values with a higher
+    /// priority than `origin` are kept.
+    pub fn undefine_variable_for_target(
+        &self,
+        target: FileName,
+        name: VariableName,
+        location: Location,
+        origin: Origin,
+    ) -> Self {
+        let existing = self
+            .target_variables
+            .get(&target)
+            .and_then(|variables| variables.get(&name));
+        if let Some(existing) = existing {
+            if existing.origin.priority() > origin.priority() {
+                return self.clone();
+            }
+        }
+        let mut tr = self.clone();
+        if let Some(variables) = tr.target_variables.get_mut(&target) {
+            variables.remove(&name);
+        }
+        tr.target_tombstones
+            .entry(target)
+            .or_default()
+            .insert(name, Tombstone::new(location, origin));
+        tr
+    }
+
+    /// Get the tombstone left by undefining a global variable, if it hasn't
+    /// been defined again since
+    pub fn get_tombstone(&self, name: VariableName) -> Option<&Tombstone> {
+        self.tombstones.get(&name)
+    }
+
+    /// Get the tombstone left by undefining a target-specific variable
+    pub fn get_tombstone_for_target(
+        &self,
+        target: FileName,
+        name: VariableName,
+    ) -> Option<&Tombstone> {
+        self.target_tombstones.get(&target)?.get(&name)
+    }
+
+    /// Iterate over the variables that decide which parts of the makefile
+    /// are interpreted at all. Changing any of them may change every rule
+    /// and variable in the database.
+    pub fn global_sensitivity(&self) -> impl Iterator<Item = &VariableName> {
+        self.global_sensitivity.iter()
+    }
+
+    /// Record that the interpretation of the makefile depends on `variables`
+    pub fn add_global_sensitivity<I>(&self, variables: I) -> Self
+    where
+        I: IntoIterator<Item = VariableName>,
+    {
+        let mut tr = self.clone();
+        tr.global_sensitivity.extend(variables);
+        tr
+    }
+
+    /// Iterate over the conditional branches that weren't interpreted, in
+    /// the order they were read. Branches nested inside another skipped
+    /// branch aren't listed separately, they are part of its lines.
+    pub fn skipped_branches(&self) -> impl Iterator<Item = &SkippedBranch> {
+        let mut branches: Vec<&(usize, SkippedBranch)> = self.skipped_branches.iter().collect();
+        branches.sort_by_key(|(order, _)| *order);
+        branches.into_iter().map(|(_, branch)| branch)
+    }
+
+    /// Record a conditional branch that wasn't interpreted
+    pub(crate) fn add_skipped_branch(&self, branch: SkippedBranch) -> Self {
+        let mut tr = self.clone();
+        let order = tr.skipped_branches.len();
+        tr.skipped_branches.insert(order, branch);
+        tr
+    }
+
+    /// Build the variables visible to the recipe for `target`, which was
+    /// reached through `parents` (the goal first, then each target whose
+    /// prerequisite led here). Target-specific variables of the parents are
+    /// inherited, with closer targets taking precedence, and the target's own
+    /// variables win over everything. Private globals and private variables
+    /// of the parents are left out, and so are variables undefined for the
+    /// target or one of its parents. See #REQ-Variable.target_specific_inherit
+    pub fn recipe_scope(&self, target: FileName, parents: &[FileName]) -> Self {
+        let mut tr = self.clone();
+        let private_globals: Vec<VariableName> = self
+            .variables
+            .iter()
+            .filter(|(_, value)| value.private)
+            .map(|(name, _)| *name)
+            .collect();
+        for name in private_globals {
+            tr.variables.remove(&name);
+        }
+
+        for (scope, inherited) in parents
+            .iter()
+            .map(|parent| (parent, true))
+            .chain(std::iter::once((&target, false)))
+        {
+            if let Some(tombstones) = self.target_tombstones.get(scope) {
+                for (name, tombstone) in tombstones.iter() {
+                    tr.variables.remove(name);
+                    tr.tombstones.insert(*name, tombstone.clone());
+                }
+            }
+            if let Some(variables) = self.target_variables.get(scope) {
+                for (name, value) in variables.iter() {
+                    if !(inherited && value.private) {
+                        tr.variables.insert(*name, value.clone());
+                        if let Some(export) = self
+                            .target_exports
+                            .get(scope)
+                            .and_then(|exports| exports.get(name))
+                        {
+                            tr.exports.insert(*name, *export);
+                        }
+                    }
+                }
+            }
+        }
+
+        tr
+    }
+
+    /// Get the origin of a variable, like the `origin` function does.
+    /// `None` means the variable is undefined.
+    pub fn variable_origin(&self, name: VariableName) -> Option<Origin> {
+        self.variables.get(&name).map(|value| value.origin)
+    }
+
+    /// Iterate over the names of all the global variables that are defined
+    pub fn variable_names(&self) -> impl Iterator<Item = VariableName> + '_ {
+        self.variables.keys().cloned()
+    }
+
     /// Get a variable based on a name
     pub fn get_variable(&self, name: VariableName) -> Option<Variable> {
         self.variables
@@ -390,10 +974,123 @@ impl Database {
             .map(|val| Variable::new(self, val))
     }
 
-    /// Get a rule for a specific target
+    /// Get the target `make` would build when no goals are given on the
+    /// command line. This is the value of `.DEFAULT_GOAL`, which defaults to
+    /// the first target that isn't a pattern rule or doesn't start with `.`.
+    /// A recursive `.DEFAULT_GOAL` is expanded when it is assigned, rather
+    /// than once all the makefiles have been read.
+    pub fn default_goal(&self) -> Option<FileName> {
+        self.default_goal
+    }
+
+    /// Get a rule for a specific target. For targets of double-colon rules,
+    /// this is the last one defined.
     pub fn get_rule(&self, target: FileName) -> Option<&Rule> {
         self.rules.get(&target)
     }
+
+    /// Get every double-colon rule for a target, in the order they were
+    /// defined. Each has its own prerequisites and recipe.
+    pub fn double_colon_rules(&self, target: FileName) -> &[Rule] {
+        self.double_colon_rules
+            .get(&target)
+            .map_or(&[], |rules| rules.as_slice())
+    }
+
+    /// Get the attributes special targets have given to a target
+    pub fn target_attributes(&self, target: FileName) -> TargetAttributes {
+        self.target_attributes
+            .get(&target)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the flags set by special targets that apply to every target
+    pub fn flags(&self) -> &GlobalFlags {
+        &self.flags
+    }
+
+    /// Check if a target was declared `.PHONY`
+    pub fn is_phony(&self, target: FileName) -> bool {
+        self.target_attributes(target).phony
+    }
+
+    /// Check if a target was declared `.PRECIOUS`
+    pub fn is_precious(&self, target: FileName) -> bool {
+        self.target_attributes(target).precious
+    }
+
+    /// Check if a target was declared `.INTERMEDIATE`
+    pub fn is_intermediate(&self, target: FileName) -> bool {
+        self.target_attributes(target).intermediate
+    }
+
+    /// Check if a target is secondary, either because it was listed in
+    /// `.SECONDARY` or because `.SECONDARY` was declared with no prerequisites
+    pub fn is_secondary(&self, target: FileName) -> bool {
+        self.flags.all_secondary || self.target_attributes(target).secondary
+    }
+
+    /// Create a copy of this database with the automatic variables available
+    /// during second expansion (`$@`, `$<`, `$^` and `$+`) bound for `rule`
+    fn with_automatic_variables(&self, names: &mut NameCache, rule: &Rule) -> Self {
+        let mut all_deps = Vec::new();
+        let mut unique_deps = Vec::new();
+        let mut seen = fxhash::FxHashSet::default();
+        for dep in &rule.deps {
+            if !all_deps.is_empty() {
+                all_deps.push(evaluated::ContentReference::space());
+            }
+            all_deps.push(evaluated::concat(Arc::clone(dep)));
+
+            if seen.insert(dep.into_string()) {
+                if !unique_deps.is_empty() {
+                    unique_deps.push(evaluated::ContentReference::space());
+                }
+                unique_deps.push(evaluated::concat(Arc::clone(dep)));
+            }
+        }
+
+        let automatic = |block: Arc<Block>| {
+            VariableParameters::new(
+                ast::preevaluated(Location::Synthetic, block),
+                Flavor::Simple,
+                Origin::Automatic,
+            )
+        };
+
+        let first_dep = rule.deps.first().cloned().unwrap_or_else(Block::empty);
+        self.set_variable(
+            names.intern_variable_name("@".into()),
+            automatic(Arc::clone(&rule.target)),
+        )
+        .set_variable(names.intern_variable_name("<".into()), automatic(first_dep))
+        .set_variable(
+            names.intern_variable_name("^".into()),
+            automatic(Block::new(Default::default(), unique_deps)),
+        )
+        .set_variable(
+            names.intern_variable_name("+".into()),
+            automatic(Block::new(Default::default(), all_deps)),
+        )
+    }
+}
+
+/// Check if a target can become the default goal. As in GNU make, targets
+/// starting with `.` are skipped unless they contain a `/`, and so are
+/// pattern rules.
+fn is_default_goal_candidate(target: &str) -> bool {
+    !target.contains('%') && (!target.starts_with('.') || target.contains('/'))
+}
+
+/// Check if a variable name can be placed in a shell environment
+fn is_exportable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
 /// Represents all the things that can go wrong while parsing an evaluating
@@ -429,6 +1126,9 @@ pub struct NameCache {
     file_names: StringInterner,
     /// All known variable names
     variable_names: StringInterner,
+    /// The full path of every makefile that has been read, keyed by the
+    /// file name used in its locations
+    makefile_paths: types::Map<FileName, PathBuf>,
 }
 
 impl NameCache {
@@ -443,6 +1143,21 @@ impl NameCache {
         FileName(trid)
     }
 
+    /// Intern the name of a makefile read from `path`, remembering the path.
+    /// The name is the full path, so that makefiles with the same name in
+    /// different directories are never confused in source locations
+    pub fn intern_makefile(&mut self, path: PathBuf) -> FileName {
+        let name = self.intern_file_name(path.to_string_lossy().into_owned());
+        self.makefile_paths.insert(name, path);
+        name
+    }
+
+    /// Get the full path of a makefile, given the file name used in its
+    /// source locations
+    pub fn makefile_path(&self, name: FileName) -> Option<&Path> {
+        self.makefile_paths.get(&name).map(PathBuf::as_path)
+    }
+
     /// Try to get a file name that has been interned
     pub fn file_name(&self, file_name: &str) -> Option<FileName> {
         self.file_names.get(file_name).map(FileName)
@@ -463,6 +1178,23 @@ impl NameCache {
     pub fn variable_name(&self, variable_name: &str) -> Option<VariableName> {
         self.variable_names.get(variable_name).map(VariableName)
     }
+
+    /// Get a variable name
+    pub fn resolve_variable_name(&self, v: VariableName) -> Option<&str> {
+        self.variable_names.resolve(v.0)
+    }
+}
+
+/// The parts of an [`Engine`](struct.Engine.html) that reading makefiles
+/// changes, saved so that a restart can read them again from scratch
+#[derive(Clone)]
+struct ReadState {
+    database: Database,
+    command_char: char,
+    vpaths: Vec<PathBuf>,
+    patterned_vpaths: pattern::PatternEngine<Vec<PathBuf>>,
+    gpaths: Vec<PathBuf>,
+    second_expansion: bool,
 }
 
 /// Represents the state of the parsing engine
@@ -477,14 +1209,80 @@ pub struct Engine {
     /// The primary vpaths. These apply to all file types.
     /// This list is maintained in the search order (i.e. `vpaths[0]` is
     /// checked, then `vpaths[1]`, etc.)
+    /// Set from `VPATH` whenever a makefile has been read, if it's defined.
     pub vpaths: Vec<PathBuf>,
 
     /// vpath patterns.
-    /// These are vpaths that only apply to files matching particular patterns
+    /// These are vpaths that only apply to files matching particular patterns.
+    /// They are set by `vpath` directives, and searched before `vpaths`.
     pub patterned_vpaths: pattern::PatternEngine<Vec<PathBuf>>,
 
-    /// The current working directory
+    /// Directories whose files keep the name found by directory search even
+    /// when they are remade.
+    /// Set from `GPATH` whenever a makefile has been read, if it's defined.
+    pub gpaths: Vec<PathBuf>,
+
+    /// The current working directory. Defaults to the process's current
+    /// directory.
     pub working_directory: PathBuf,
+
+    /// True once `.SECONDEXPANSION` has been declared. Prerequisites of rules
+    /// read while this is set are deferred to
+    /// [`expand_secondary_prerequisites`](#method.expand_secondary_prerequisites)
+    pub second_expansion: bool,
+
+    /// Directories searched, in order, for included makefiles that aren't
+    /// found relative to the working directory (`make -I`)
+    pub include_directories: Vec<PathBuf>,
+
+    /// Whether GNU make's default include directories (`/usr/include` and
+    /// friends) are searched after `include_directories`
+    pub search_default_include_directories: bool,
+
+    /// Where makefiles are read from, and where questions about files
+    /// (existence, modification times, wildcards) are answered.
+    /// Defaults to the real file system.
+    pub filesystem: Arc<dyn FileSystem>,
+
+    /// The goals given on the command line, reported in `MAKECMDGOALS`
+    pub command_line_goals: Vec<String>,
+
+    /// The depth of recursive make invocations, reported in `MAKELEVEL`.
+    /// The top-level make is at level 0.
+    pub make_level: u32,
+
+    /// The name make was invoked as, reported in `MAKE`
+    pub make_command: String,
+
+    /// When set, included makefiles that don't exist don't stop reading, even
+    /// without `-include`. Like GNU make, they are recorded so that we can
+    /// work out which ones would be remade before restarting, see
+    /// [`remake_plan`](#method.remake_plan). Nothing is ever executed.
+    pub remake_makefiles: bool,
+
+    /// Diagnostics accumulated while reading makefiles, in the order they
+    /// were generated
+    diagnostics: Vec<Diagnostic>,
+
+    /// Makefiles read because of `include` directives, in the order they
+    /// were read
+    includes: Vec<Include>,
+
+    /// The makefiles that have been read, in the order they were read.
+    /// This is the value of `MAKEFILE_LIST`.
+    makefile_list: Vec<String>,
+
+    /// Included makefiles that couldn't be found, in the order they were
+    /// included
+    missing_includes: Vec<MissingInclude>,
+
+    /// Everything reading makefiles changes, as it was before the first
+    /// makefile was read, which is where a restart starts from
+    initial_state: Option<ReadState>,
+
+    /// How many times reading has been restarted. This is the value of
+    /// `MAKE_RESTARTS`.
+    restarts: u32,
 }
 
 impl Default for Engine {
@@ -494,8 +1292,24 @@ impl Default for Engine {
             command_char: '\t',
             vpaths: Default::default(),
             patterned_vpaths: Default::default(),
-            working_directory: std::env::current_dir()
-                .expect("Failed to get current working directory"),
+            gpaths: Vec::new(),
+            // Engines reading from an in-memory file system don't need a
+            // real working directory, so don't fail without one
+            working_directory: std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+            second_expansion: false,
+            include_directories: Vec::new(),
+            search_default_include_directories: true,
+            filesystem: Arc::new(OsFileSystem),
+            command_line_goals: Vec::new(),
+            make_level: 0,
+            make_command: "make".into(),
+            remake_makefiles: false,
+            diagnostics: Vec::new(),
+            includes: Vec::new(),
+            makefile_list: Vec::new(),
+            missing_includes: Vec::new(),
+            initial_state: None,
+            restarts: 0,
         }
     }
 }
@@ -504,11 +1318,340 @@ impl Engine {
     // Gets the actual path for a file, given a file name
     /// This is used for both prereq and target name searches
     /// See [the GNU make manual](https://www.gnu.org/software/make/manual/make.html#Directory-Search)
-    /// for details
-    pub fn get_path_for_filename(&self, _filename: &str) -> PathBuf {
-        // match against unconditional vpaths, and then the pattern engine vpaths
-        // https://www.gnu.org/software/make/manual/make.html#Search-Algorithm
-        unimplemented!("get_path_for_filename")
+    /// for details.
+    /// If the file exists under its own name (or can't be found at all) the
+    /// name is returned unchanged. Whether make keeps using the path found
+    /// by the search depends on whether the file is remade, see
+    /// [`resolve_file_path`](#method.resolve_file_path).
+    pub fn get_path_for_filename(&self, filename: &str) -> PathBuf {
+        match self.search_directory(filename) {
+            Some(directory) => directory.join(filename),
+            None => PathBuf::from(filename),
+        }
+    }
+
+    /// Find the directory a file was found in by directory search, if it
+    /// was. Patterned vpaths are searched (in the order they were given)
+    /// before the general `vpaths`, like GNU make does.
+    fn search_directory(&self, filename: &str) -> Option<PathBuf> {
+        if Path::new(filename).is_absolute() || self.file_exists(filename) {
+            return None;
+        }
+
+        self.patterned_vpaths
+            .matches(filename)
+            .into_iter()
+            .flatten()
+            .chain(self.vpaths.iter().cloned())
+            .find(|directory| self.file_exists(&directory.join(filename).to_string_lossy()))
+    }
+
+    /// Get the path make ends up using for a file. A path found by directory
+    /// search is only kept if the file doesn't need to be remade (judged by
+    /// the modification times of it and its prerequisites), or if it was
+    /// found in one of the `gpaths`. Otherwise the file is remade under the
+    /// name given in the makefile.
+    /// See [the GNU make manual](https://www.gnu.org/software/make/manual/make.html#Search-Algorithm)
+    pub fn resolve_file_path(&self, names: &NameCache, filename: &str) -> PathBuf {
+        self.resolve_file(names, filename, &mut Vec::new(), &mut Default::default())
+            .0
+    }
+
+    /// Work out the path used for a file, and whether it would be remade.
+    /// `visiting` holds the files whose prerequisites are being examined, to
+    /// break dependency loops. `resolved` remembers the answer for each file
+    /// already worked out, so files shared by several prerequisite chains are
+    /// only examined once.
+    fn resolve_file(
+        &self,
+        names: &NameCache,
+        filename: &str,
+        visiting: &mut Vec<FileName>,
+        resolved: &mut fxhash::FxHashMap<FileName, (PathBuf, bool)>,
+    ) -> (PathBuf, bool) {
+        let file = names.file_name(filename);
+        if let Some(known) = file.and_then(|file| resolved.get(&file)) {
+            return known.clone();
+        }
+        // Phony targets are never searched for, and are always remade
+        if file.is_some_and(|file| self.database.is_phony(file)) {
+            return (PathBuf::from(filename), true);
+        }
+
+        let directory = self.search_directory(filename);
+        let path = match &directory {
+            Some(directory) => directory.join(filename),
+            None => PathBuf::from(filename),
+        };
+        let modified = self.modification_time(&path.to_string_lossy()).ok();
+
+        let rule = match file {
+            Some(file) if !visiting.contains(&file) => {
+                self.database.get_rule(file).map(|rule| (file, rule))
+            }
+            _ => None,
+        };
+        let (file, rule) = match rule {
+            Some(rule) => rule,
+            // Nothing can remake the file, so it's only out of date if it
+            // doesn't exist
+            None => return (path, modified.is_none()),
+        };
+
+        visiting.push(file);
+        let mut remade = modified.is_none();
+        for dependency in rule.dependencies() {
+            let (dependency_path, dependency_remade) =
+                self.resolve_file(names, &dependency.into_string(), visiting, resolved);
+            let newer = match (
+                modified,
+                self.modification_time(&dependency_path.to_string_lossy()),
+            ) {
+                (Some(modified), Ok(dependency_modified)) => dependency_modified > modified,
+                _ => true,
+            };
+            remade |= dependency_remade || newer;
+        }
+        visiting.pop();
+
+        let keep_path = match &directory {
+            Some(directory) => !remade || self.gpaths.contains(directory),
+            None => true,
+        };
+        let tr = if keep_path {
+            (path, remade)
+        } else {
+            (PathBuf::from(filename), remade)
+        };
+        resolved.insert(file, tr.clone());
+        tr
+    }
+
+    /// Update `vpaths` and `gpaths` from `VPATH` and `GPATH`, if they are
+    /// defined
+    fn update_search_paths(&mut self, names: &mut NameCache) {
+        if let Some(directories) = self.search_path_variable(names, "VPATH") {
+            self.vpaths = directories;
+        }
+        if let Some(directories) = self.search_path_variable(names, "GPATH") {
+            self.gpaths = directories;
+        }
+    }
+
+    /// Expand a variable holding a search path, if it is defined
+    fn search_path_variable(&mut self, names: &mut NameCache, name: &str) -> Option<Vec<PathBuf>> {
+        let variable = self.database.get_variable(names.variable_name(name)?)?;
+        let value = variable.ast().clone();
+        Some(split_search_path(&value.eval(names, self).into_string()))
+    }
+
+    /// Get the directories searched for included makefiles, in order: the
+    /// `include_directories`, then the default directories (if enabled).
+    /// Like GNU make, directories that don't exist are left out. This is also
+    /// the value of `.INCLUDE_DIRS`.
+    pub fn include_search_path(&self) -> Vec<PathBuf> {
+        let mut search_path: Vec<PathBuf> = self
+            .include_directories
+            .iter()
+            .map(|directory| clean_path(&self.working_directory.join(directory)))
+            .filter(|directory| self.filesystem.is_dir(directory))
+            .collect();
+        if self.search_default_include_directories {
+            search_path.extend(
+                DEFAULT_INCLUDE_DIRECTORIES
+                    .iter()
+                    .map(PathBuf::from)
+                    .filter(|directory| self.filesystem.is_dir(directory)),
+            );
+        }
+        search_path
+    }
+
+    /// Find the makefile named by an `include` directive. Like GNU make, the
+    /// name is first tried as is (relative to the working directory), then
+    /// relative names are looked up in each directory of the
+    /// [search path](#method.include_search_path).
+    /// Returns the path of the makefile, and the search directory it was
+    /// found in, if any.
+    pub(crate) fn resolve_include(&self, name: &str) -> Option<(PathBuf, Option<PathBuf>)> {
+        let direct = clean_path(&self.working_directory.join(name));
+        if self.filesystem.is_file(&direct) {
+            return Some((direct, None));
+        }
+        if Path::new(name).is_absolute() {
+            return None;
+        }
+
+        self.include_search_path()
+            .into_iter()
+            .map(|directory| (directory.join(name), Some(directory)))
+            .find(|(path, _)| self.filesystem.is_file(path))
+    }
+
+    /// Check whether a file exists, relative to the working directory
+    pub fn file_exists(&self, name: &str) -> bool {
+        self.filesystem
+            .exists(&clean_path(&self.working_directory.join(name)))
+    }
+
+    /// Get the modification time of a file, relative to the working directory
+    pub fn modification_time(&self, name: &str) -> io::Result<SystemTime> {
+        self.filesystem
+            .modified(&clean_path(&self.working_directory.join(name)))
+    }
+
+    /// Expand a shell glob like `$(wildcard)`, relative to the working
+    /// directory. The results are sorted, and names without glob characters
+    /// are kept only if they exist.
+    pub fn wildcard(&self, pattern: &str) -> Vec<String> {
+        filesystem::wildcard(&*self.filesystem, &self.working_directory, pattern)
+    }
+
+    /// Get the makefiles read because of `include` directives so far
+    pub fn includes(&self) -> &[Include] {
+        &self.includes
+    }
+
+    /// Record a makefile read because of an `include` directive
+    pub(crate) fn push_include(&mut self, include: Include) {
+        self.includes.push(include);
+    }
+
+    /// Get the included makefiles that couldn't be found so far
+    pub fn missing_includes(&self) -> &[MissingInclude] {
+        &self.missing_includes
+    }
+
+    /// Record an included makefile that couldn't be found
+    pub(crate) fn push_missing_include(&mut self, include: MissingInclude) {
+        self.missing_includes.push(include);
+    }
+
+    /// Work out what GNU make would do about the missing included makefiles
+    /// once all the makefiles have been read: which ones rules could build
+    /// (after which make would restart), and which would be an error
+    pub fn remake_plan(&self, names: &NameCache) -> RemakePlan {
+        RemakePlan::new(names, self, &self.missing_includes)
+    }
+
+    /// Throw away everything read so far, like GNU make does after remaking
+    /// makefiles, so that the makefiles can be read again. `generated` gives
+    /// the contents of the makefiles that would have been built (usually
+    /// those named by [`RemakePlan::to_build`](remake/struct.RemakePlan.html#method.to_build)),
+    /// relative to the working directory. They are layered over the current
+    /// file system.
+    pub fn restart<I, P, C>(&mut self, generated: I)
+    where
+        I: IntoIterator<Item = (P, C)>,
+        P: AsRef<Path>,
+        C: Into<String>,
+    {
+        let mut upper = MemoryFileSystem::new();
+        for (name, contents) in generated {
+            upper.add_file(self.working_directory.join(name), contents);
+        }
+        self.filesystem = Arc::new(OverlayFileSystem::new(upper, self.filesystem.clone()));
+
+        if let Some(initial) = self.initial_state.take() {
+            self.database = initial.database;
+            self.command_char = initial.command_char;
+            self.vpaths = initial.vpaths;
+            self.patterned_vpaths = initial.patterned_vpaths;
+            self.gpaths = initial.gpaths;
+            self.second_expansion = initial.second_expansion;
+        }
+        self.diagnostics.clear();
+        self.includes.clear();
+        self.makefile_list.clear();
+        self.missing_includes.clear();
+        self.restarts += 1;
+    }
+
+    /// Get the number of times reading has been restarted
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Set one of the variables make defines itself, unless the makefile
+    /// (or the command line, etc.) has already given it a value. Like GNU
+    /// make, `CURDIR` and `MAKELEVEL` replace values from the environment.
+    fn set_special_variable(
+        &mut self,
+        names: &mut NameCache,
+        name: &str,
+        value: String,
+        origin: Origin,
+    ) {
+        let replaces_environment = name == "CURDIR" || name == "MAKELEVEL";
+        let name = names.intern_variable_name(name.into());
+        if let Some(existing) = self.database.variable_origin(name) {
+            if existing != origin && !(replaces_environment && existing == Origin::Environment) {
+                return;
+            }
+        }
+        self.database = self.database.set_variable(
+            name,
+            VariableParameters::new(
+                ast::constant(LocatedString::synthetic_new(value)),
+                Flavor::Simple,
+                origin,
+            ),
+        );
+    }
+
+    /// Set the variables make maintains while reading makefiles
+    /// (`MAKEFILE_LIST`, `CURDIR`, `.INCLUDE_DIRS`, etc.)
+    fn set_special_variables(&mut self, names: &mut NameCache) {
+        let makefile_list = self.makefile_list.join(" ");
+        self.set_special_variable(names, "MAKEFILE_LIST", makefile_list, Origin::File);
+
+        let curdir = self.working_directory.to_string_lossy().into_owned();
+        self.set_special_variable(names, "CURDIR", curdir, Origin::Default);
+
+        let goals = self.command_line_goals.join(" ");
+        self.set_special_variable(names, "MAKECMDGOALS", goals, Origin::Default);
+
+        self.set_special_variable(names, "MAKE_VERSION", MAKE_VERSION.into(), Origin::Default);
+        self.set_special_variable(names, ".FEATURES", FEATURES.into(), Origin::Default);
+
+        let make_level = self.make_level.to_string();
+        self.set_special_variable(names, "MAKELEVEL", make_level, Origin::Default);
+
+        let make_command = self.make_command.clone();
+        self.set_special_variable(names, "MAKE", make_command, Origin::Default);
+
+        // Like GNU make, this is only defined once make has restarted
+        if self.restarts > 0 {
+            let restarts = self.restarts.to_string();
+            self.set_special_variable(names, "MAKE_RESTARTS", restarts, Origin::Default);
+        }
+
+        let include_dirs = self
+            .include_search_path()
+            .iter()
+            .map(|directory| directory.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.set_special_variable(names, ".INCLUDE_DIRS", include_dirs, Origin::Default);
+
+        self.set_variables_list(names);
+    }
+
+    /// Update `.VARIABLES`. GNU make computes it whenever it is expanded; we
+    /// refresh it when a makefile starts and finishes being read.
+    fn set_variables_list(&mut self, names: &mut NameCache) {
+        // Make sure `.VARIABLES` lists itself
+        let variables_name = names.intern_variable_name(".VARIABLES".into());
+        let mut variables: Vec<&str> = self
+            .database
+            .variable_names()
+            .chain(std::iter::once(variables_name))
+            .filter_map(|name| names.resolve_variable_name(name))
+            .collect();
+        variables.sort();
+        variables.dedup();
+        let variables = variables.join(" ");
+        self.set_special_variable(names, ".VARIABLES", variables, Origin::Default);
     }
 
     /// Update the internal database.
@@ -516,22 +1659,323 @@ impl Engine {
         self.database = db;
     }
 
+    /// Get all the diagnostics generated so far
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Record a new diagnostic
+    pub(crate) fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
     /// Add a rule to the database
     fn from_protorule(&mut self, names: &mut NameCache, rule: parsers::ProtoRule) {
-        self.database = self.database.from_protorule(names, rule);
+        let (database, diagnostics) = self.database.from_protorule(names, rule);
+        self.database = database;
+        for diagnostic in diagnostics {
+            self.push_diagnostic(diagnostic);
+        }
+    }
+
+    /// Keep state derived from special variables in sync after a global
+    /// variable was set or undefined, however that happened
+    pub(crate) fn global_variable_changed(&mut self, names: &mut NameCache, name: VariableName) {
+        if names.variable_name(".RECIPEPREFIX") == Some(name) {
+            self.update_recipe_prefix(names);
+        } else if names.variable_name(".DEFAULT_GOAL") == Some(name) {
+            self.update_default_goal(names);
+        }
     }
 
-    /// Parses a makefile
+    /// Update the database's default goal from the current value of
+    /// `.DEFAULT_GOAL`. GNU make rejects a value with more than one word;
+    /// here only the first word is used.
+    fn update_default_goal(&mut self, names: &mut NameCache) {
+        let value = names
+            .variable_name(".DEFAULT_GOAL")
+            .and_then(|name| self.database.get_variable(name))
+            .map(|variable| variable.ast().clone());
+        let goal = match value {
+            Some(ast) => ast.eval(names, self).into_string(),
+            None => String::new(),
+        };
+        self.database.default_goal = goal
+            .split_whitespace()
+            .next()
+            .map(|goal| names.intern_file_name(goal.into()));
+        debug!("Default goal is now {:?}", self.database.default_goal);
+    }
+
+    /// Update the recipe prefix from the current value of `.RECIPEPREFIX`.
+    /// An empty (or undefined) prefix means the default, a tab.
+    fn update_recipe_prefix(&mut self, names: &mut NameCache) {
+        let prefix = names
+            .variable_name(".RECIPEPREFIX")
+            .and_then(|name| self.database.get_variable(name))
+            .map(|variable| variable.ast().clone());
+        let prefix = match prefix {
+            Some(ast) => ast.eval(names, self).into_string(),
+            None => String::new(),
+        };
+        self.command_char = prefix.chars().next().unwrap_or('\t');
+        debug!("Recipe prefix is now {:?}", self.command_char);
+    }
+
+    /// Expand the recipe of a rule into the scripts that would be handed to
+    /// the shell, with `$@`, `$<`, `$^` and `$+` bound for the rule and
+    /// target-specific variables in scope (see
+    /// [`Database::recipe_scope`](struct.Database.html#method.recipe_scope)
+    /// for `parents`). Normally every command line becomes its own script. Once `.ONESHELL`
+    /// has been declared, the whole recipe becomes a single script, and only
+    /// the prefix characters of its first line are interpreted.
+    pub fn expand_recipe(
+        &mut self,
+        names: &mut NameCache,
+        rule: &Rule,
+        parents: &[FileName],
+    ) -> Vec<ExpandedCommand> {
+        let saved_database = self.database.clone();
+        self.database = saved_database
+            .recipe_scope(rule.target_fname, parents)
+            .with_automatic_variables(names, rule);
+        let lines: Vec<String> = rule
+            .recipe
+            .commands()
+            .iter()
+            .map(|command| command.unexpanded_command.eval(names, self).into_string())
+            .collect();
+        self.database = saved_database;
+
+        if !self.database.flags().one_shell {
+            return lines
+                .iter()
+                .map(|line| ExpandedCommand::from_line(line))
+                .collect();
+        }
+
+        let mut lines = lines.iter();
+        let mut script = match lines.next() {
+            Some(first) => ExpandedCommand::from_line(first),
+            None => return Vec::new(),
+        };
+        for line in lines {
+            script.script.push('\n');
+            script.script.push_str(line);
+        }
+        vec![script]
+    }
+
+    /// Define a variable as if it had been passed on the command line, as in
+    /// `make NAME=value`. `definition` may use any assignment operator.
+    /// Command line variables take priority over assignments in makefiles,
+    /// unless those use `override`.
+    pub fn define_command_line_variable(
+        &mut self,
+        names: &mut NameCache,
+        definition: &str,
+    ) -> Result<(), MakefileError> {
+        parsers::variable::define_command_line_variable(names, self, definition)
+            .map_err(MakefileError::ParseError)
+    }
+
+    /// Import variables from an environment, such as the one a CI job ran
+    /// in. Nothing is imported from the process environment implicitly.
+    /// Normally makefile assignments replace environment values. When
+    /// `environment_overrides` is set (like `make -e`), environment values
+    /// take priority over assignments in makefiles instead, though `override`
+    /// and the command line still win.
+    /// As in GNU make, `SHELL` is never taken from the environment.
+    pub fn import_environment<I, K, V>(
+        &mut self,
+        names: &mut NameCache,
+        environment: I,
+        environment_overrides: bool,
+    ) where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let origin = if environment_overrides {
+            Origin::EnvironmentOverride
+        } else {
+            Origin::Environment
+        };
+
+        for (name, value) in environment {
+            let name = name.as_ref();
+            if name == "SHELL" {
+                continue;
+            }
+            let name = names.intern_variable_name(name.into());
+            if self.database.blocking_origin(name, origin).is_some() {
+                continue;
+            }
+
+            // Environment values are recursively expanded, but may not be
+            // valid makefile syntax
+            let value = Block::new(
+                Default::default(),
+                vec![evaluated::constant(LocatedString::synthetic_new(
+                    value.as_ref(),
+                ))],
+            );
+            let ast = match parsers::ast::parse_ast(value.span()) {
+                Ok((rest, ast)) if rest.len() == 0 => ast,
+                _ => ast::preevaluated(Location::Synthetic, Arc::clone(&value)),
+            };
+
+            self.database = self.database.set_variable(
+                name,
+                VariableParameters::new(ast, Flavor::Recursive, origin),
+            );
+            self.global_variable_changed(names, name);
+        }
+    }
+
+    /// Compute the environment a recipe for `target` would receive, mapping
+    /// each exported variable's name to its expanded value.
+    /// Variables are looked up in the
+    /// [`recipe_scope`](struct.Database.html#method.recipe_scope) of the
+    /// target, so target-specific variables (and their `export` state) take
+    /// precedence over global ones, and `$@`, `$<`, `$^` and `$+` are bound while
+    /// expanding values. Variables whose names aren't valid shell identifiers
+    /// are never exported.
+    pub fn recipe_environment(
+        &mut self,
+        names: &mut NameCache,
+        target: FileName,
+        parents: &[FileName],
+    ) -> BTreeMap<String, String> {
+        let saved_database = self.database.clone();
+        let scope = saved_database.recipe_scope(target, parents);
+        let database = match saved_database.rules.get(&target) {
+            Some(rule) => scope.with_automatic_variables(names, rule),
+            None => scope,
+        };
+
+        let candidates: Vec<(VariableName, VariableParameters)> = database
+            .variables
+            .iter()
+            .filter(|(name, value)| {
+                database.export_state(database.exports.get(name).cloned(), value)
+            })
+            .map(|(name, value)| (*name, value.clone()))
+            .collect();
+        self.database = database;
+
+        let mut tr = BTreeMap::new();
+        for (name, value) in candidates {
+            let name = match names.resolve_variable_name(name) {
+                Some(name) if is_exportable_name(name) => name.to_owned(),
+                _ => continue,
+            };
+            let value = value.unexpanded_value.eval(names, self).into_string();
+            tr.insert(name, value);
+        }
+
+        self.database = saved_database;
+        tr
+    }
+
+    /// Run the second expansion pass over every rule with prerequisites
+    /// declared after `.SECONDEXPANSION`. Each set of deferred prerequisites
+    /// is expanded with `$@`, `$<`, `$^` and `$+` bound for the rule's target,
+    /// and the results are appended to the rule's dependencies. The produced
+    /// blocks wrap a [`SecondExpansion`](evaluated/struct.SecondExpansion.html)
+    /// node, so both expansion stages remain visible.
+    /// This should be called once all makefiles have been read.
+    pub fn expand_secondary_prerequisites(&mut self, names: &mut NameCache) {
+        use crate::parsers::file_sequence::{parse_file_seq, FileSeqParseOptions};
+
+        let targets: Vec<FileName> = self
+            .database
+            .rules
+            .values()
+            .filter(|rule| !rule.secondary_deps.is_empty())
+            .map(|rule| rule.target_fname)
+            .collect();
+
+        for target in targets {
+            let mut rule = self.database.rules[&target].clone();
+            let saved_database = self.database.clone();
+
+            for secondary in std::mem::take(&mut rule.secondary_deps) {
+                self.database = saved_database.with_automatic_variables(names, &rule);
+                let value = secondary.unexpanded.eval(names, self);
+                let expanded = Block::new(
+                    value.raw_sensitivity(),
+                    vec![evaluated::second_expansion(
+                        Arc::clone(&secondary.first_expansion),
+                        value,
+                    )],
+                );
+
+                let deps = parse_file_seq(
+                    expanded.span(),
+                    FileSeqParseOptions {
+                        extra_stopchars: ":",
+                        ..Default::default()
+                    },
+                );
+                for dep in deps {
+                    rule.dep_names
+                        .insert(names.intern_file_name(dep.into_string()));
+                    rule.deps.push(dep);
+                }
+            }
+
+            self.database = saved_database;
+            self.database.rules.insert(target, rule);
+        }
+    }
+
+    /// Parses a makefile. `input_filename` names the makefile relative to the
+    /// working directory. Its full path is what appears in the source
+    /// locations of everything read from it, see
+    /// [`NameCache::makefile_path`](struct.NameCache.html#method.makefile_path).
+    /// The name itself is appended to `MAKEFILE_LIST` before the makefile is
+    /// read.
     pub fn read_makefile<F: BufRead>(
         &mut self,
         names: &mut NameCache,
         input: &mut F,
         input_filename: &str,
+    ) -> Result<(), MakefileError> {
+        self.read_makefile_within(names, input, input_filename, Default::default())
+    }
+
+    /// Parse a makefile included by a line whose interpretation depends on
+    /// `enclosing`. Everything in the makefile is globally sensitive to those
+    /// variables too.
+    pub(crate) fn read_makefile_within<F: BufRead>(
+        &mut self,
+        names: &mut NameCache,
+        input: &mut F,
+        input_filename: &str,
+        enclosing: types::Set<VariableName>,
     ) -> Result<(), MakefileError> {
         info!("Begin reading makefile {:?}", input_filename);
 
+        if self.makefile_list.is_empty() {
+            self.initial_state = Some(ReadState {
+                database: self.database.clone(),
+                command_char: self.command_char,
+                vpaths: self.vpaths.clone(),
+                patterned_vpaths: self.patterned_vpaths.clone(),
+                gpaths: self.gpaths.clone(),
+                second_expansion: self.second_expansion,
+            });
+        }
+        self.makefile_list.push(input_filename.into());
+        self.set_special_variables(names);
+
         let mut i = String::new();
         input.read_to_string(&mut i)?;
+        // Dependency files generated by compilers get a fast path
+        let simple_rules = parsers::dependency_file::scan(&i, self.command_char);
+        let path = clean_path(&self.working_directory.join(input_filename));
+        let filename = names.intern_makefile(path);
         let input_block = Block::new(
             Default::default(),
             vec![evaluated::ContentReference::new_from_node(Arc::new(
@@ -539,7 +1983,7 @@ impl Engine {
                     source_location::Location::SourceLocation {
                         character: 1,
                         line: 1,
-                        filename: names.intern_file_name(input_filename.into()),
+                        filename,
                     }
                     .into(),
                     i,
@@ -547,7 +1991,16 @@ impl Engine {
             ))],
         );
 
-        self.process_block(names, &input_block)?;
+        match simple_rules {
+            Some(rules) => {
+                debug!("Reading {:?} as a dependency file", input_filename);
+                parsers::dependency_file::process(&input_block, rules, names, self, enclosing)
+                    .map_err(MakefileError::ParseError)?
+            }
+            None => self.process_block_within(names, &input_block, enclosing)?,
+        }
+        self.set_variables_list(names);
+        self.update_search_paths(names);
 
         info!("Complete read of makefile {:?}", input_filename);
 
@@ -559,9 +2012,20 @@ impl Engine {
         &mut self,
         names: &mut NameCache,
         block: &Block,
+    ) -> Result<(), MakefileError> {
+        self.process_block_within(names, block, Default::default())
+    }
+
+    /// Process a block of makefile text produced by a line (such as an
+    /// `$(eval)`) whose interpretation depends on `enclosing`
+    pub(crate) fn process_block_within(
+        &mut self,
+        names: &mut NameCache,
+        block: &Block,
+        enclosing: types::Set<VariableName>,
     ) -> Result<(), MakefileError> {
         use nom::Err as NErr;
-        let mut parser_state = parsers::ParserState::new();
+        let mut parser_state = parsers::ParserState::for_block(block, enclosing);
 
         let mut i = block.span();
 
@@ -588,7 +2052,9 @@ impl Engine {
             i = new_i;
         }
 
-        Ok(())
+        parser_state
+            .finish(names, self)
+            .map_err(MakefileError::ParseError)
     }
 }
 
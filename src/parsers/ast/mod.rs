@@ -123,7 +123,9 @@ fn advanced_var<'a>(
     let name_node = name_node.slice(..name_node.len() - 1);
 
     match function_call(name_node, dollar_location.clone()) {
-        Ok(v) => Ok(v),
+        // The function call only consumes the inside of the reference, so
+        // continue from after the terminating character
+        Ok((_, v)) => Ok((i, v)),
         Err(Err::Failure(context)) => {
             if context.clone().into_error_kind()
                 == nom::ErrorKind::Custom(ParseErrorKind::InternalFailure("not a function call"))
@@ -175,6 +177,8 @@ fn function_call<'a>(
             | func_entry!("strip", strip)
             | func_entry!("words", words)
             | func_entry!("word", word)
+            | func_entry!("dir", dir)
+            | func_entry!("lastword", last_word)
             | pe_complete!(no_such_function)
     )
 }
@@ -260,6 +264,34 @@ fn words<'a>(
     Ok((i, ast::words(start_location, arg)))
 }
 
+fn dir<'a>(
+    i: BlockSpan<'a>,
+    start_location: Location,
+) -> IResult<BlockSpan<'a>, AstNode, ParseErrorKind> {
+    let (i, arg) = function_argument(i)?;
+    if i.len() != 0 {
+        return fail_out(i, ParseErrorKind::ExtraArguments("dir"));
+    }
+
+    let (_, arg) = parse_ast(arg)?;
+
+    Ok((i, ast::dir(start_location, arg)))
+}
+
+fn last_word<'a>(
+    i: BlockSpan<'a>,
+    start_location: Location,
+) -> IResult<BlockSpan<'a>, AstNode, ParseErrorKind> {
+    let (i, arg) = function_argument(i)?;
+    if i.len() != 0 {
+        return fail_out(i, ParseErrorKind::ExtraArguments("lastword"));
+    }
+
+    let (_, arg) = parse_ast(arg)?;
+
+    Ok((i, ast::last_word(start_location, arg)))
+}
+
 fn word<'a>(
     i: BlockSpan<'a>,
     start_location: Location,
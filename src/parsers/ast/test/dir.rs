@@ -0,0 +1,45 @@
+//! Tests for the `dir` function
+
+use super::*;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn basic() {
+    let block = create_span("$(dir src/foo.c hacks)");
+    let res = assert_ok!(parse_ast(block.span()));
+    assert_complete!(res.0);
+    assert_eq!(
+        res.1,
+        ast::dir(
+            Location::test_location(1, 1),
+            ast::constant(LocatedString::test_new(1, 7, "src/foo.c hacks"))
+        )
+    )
+}
+
+#[test]
+fn extra_arguments() {
+    let block = create_span("$(dir a,b)");
+    let err = assert_err!(parse_ast(block.span()));
+    assert_err_contains!(err, ParseErrorKind::ExtraArguments("dir"));
+}
+
+#[test]
+fn trailing_content() {
+    let block = create_span("$(dir a/b)c");
+    let res = assert_ok!(parse_ast(block.span()));
+    assert_complete!(res.0);
+    assert_eq!(
+        res.1,
+        ast::collapsing_concat(
+            Location::test_location(1, 1),
+            vec![
+                ast::dir(
+                    Location::test_location(1, 1),
+                    ast::constant(LocatedString::test_new(1, 7, "a/b"))
+                ),
+                ast::constant(LocatedString::test_new(1, 11, "c")),
+            ]
+        )
+    )
+}
@@ -0,0 +1,25 @@
+//! Tests for the `lastword` function
+
+use super::*;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn basic() {
+    let block = create_span("$(lastword a b c)");
+    let res = assert_ok!(parse_ast(block.span()));
+    assert_complete!(res.0);
+    assert_eq!(
+        res.1,
+        ast::last_word(
+            Location::test_location(1, 1),
+            ast::constant(LocatedString::test_new(1, 12, "a b c"))
+        )
+    )
+}
+
+#[test]
+fn extra_arguments() {
+    let block = create_span("$(lastword a,b)");
+    let err = assert_err!(parse_ast(block.span()));
+    assert_err_contains!(err, ParseErrorKind::ExtraArguments("lastword"));
+}
@@ -5,6 +5,8 @@ use crate::parsers::test::create_span;
 use crate::source_location::{LocatedString, Location};
 
 mod arguments;
+mod dir;
+mod last_word;
 mod proptest;
 mod strip;
 mod word;
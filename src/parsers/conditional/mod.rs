@@ -4,10 +4,14 @@
 
 use super::makefile_whitespace;
 use crate::ast::AstNode;
-use crate::evaluated::BlockSpan;
+use crate::evaluated::{Block, BlockSpan};
 use crate::parsers::ast::parse_ast;
-use crate::ParseErrorKind;
+use crate::parsers::{ConditionalInterpretation, ConditionalState};
+use crate::skipped_branch::{SkipReason, SkippedBranch};
+use crate::types::Set;
+use crate::{ParseErrorKind, VariableName};
 use nom::{Context, Err, ErrorKind, IResult};
+use std::sync::Arc;
 
 #[cfg(test)]
 mod test;
@@ -33,73 +37,185 @@ impl Conditional {
 }
 
 impl crate::parsers::ParserState {
-    /// Handle a conditional that came back from line parsing
-    pub(super) fn handle_conditional(
+    /// Handle a conditional directive `line`, keeping track of the branches
+    /// it causes to be skipped
+    pub(super) fn handle_conditional_line(
         &mut self,
         conditional: Conditional,
+        line: &Arc<Block>,
         names: &mut crate::NameCache,
         engine: &mut crate::Engine,
     ) -> Result<(), ParseErrorKind> {
-        let conditional_is_inverted = conditional.is_inverted();
-        match conditional {
-            Conditional::IfEq(a, b) | Conditional::IfNEq(a, b) => {
-                let a = a.eval(names, engine);
-                let b = b.eval(names, engine);
+        // The depth of the conditional this directive belongs to
+        let depth = match conditional {
+            Conditional::Else(_) | Conditional::EndIf => self.conditionals.len(),
+            _ => self.conditionals.len() + 1,
+        };
+        self.handle_conditional(conditional, names, engine)?;
 
-                let a = a.into_string();
-                let b = b.into_string();
-                let a = a.trim();
-                let b = b.trim();
+        match &mut self.skipped_branch {
+            Some((skipped_depth, branch)) if depth > *skipped_depth => {
+                // Nested inside the skipped branch, so it's just another line
+                branch.push_line(Arc::clone(line));
+                return Ok(());
+            }
+            Some(_) => {
+                // The skipped branch ends here
+                let (_, branch) = self.skipped_branch.take().unwrap();
+                engine.database = engine.database.add_skipped_branch(branch);
+            }
+            None => {}
+        }
 
-                let conditional_mode = if a == b {
-                    super::ConditionalInterpretation::Executing
-                } else {
-                    super::ConditionalInterpretation::NotExecuting
-                };
-                let conditional_mode = if conditional_is_inverted {
-                    conditional_mode.invert()
-                } else {
-                    conditional_mode
+        if self.ignoring {
+            let reason = match self.conditionals.last().map(|c| c.interpretation) {
+                Some(ConditionalInterpretation::AlreadyExecuted) => SkipReason::EarlierBranchTaken,
+                _ => SkipReason::ConditionFalse,
+            };
+            self.skipped_branch = Some((
+                self.conditionals.len(),
+                SkippedBranch::new(Arc::clone(line), reason),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Handle a conditional that came back from line parsing
+    fn handle_conditional(
+        &mut self,
+        conditional: Conditional,
+        names: &mut crate::NameCache,
+        engine: &mut crate::Engine,
+    ) -> Result<(), ParseErrorKind> {
+        match conditional {
+            Conditional::Else(followup) => {
+                let outer_ignoring = match self.conditionals.split_last() {
+                    Some((_, outer)) => outer
+                        .iter()
+                        .any(|c| c.interpretation != ConditionalInterpretation::Executing),
+                    None => return Err(ParseErrorKind::UnattachedElse),
                 };
+                let last_idx = self.conditionals.len() - 1;
+                if self.conditionals[last_idx].seen_else {
+                    return Err(ParseErrorKind::TooManyElses);
+                }
+                let is_bare_else = followup.is_none();
 
-                self.conditionals.push(super::ConditionalState {
-                    interpretation: conditional_mode,
-                    seen_else: false,
-                });
-                self.update_ignoring();
+                // Only the first branch of the chain whose condition holds
+                // is interpreted
+                let (interpretation, sensitivity) =
+                    if self.conditionals[last_idx].taken_branch.is_some() {
+                        (ConditionalInterpretation::AlreadyExecuted, Set::default())
+                    } else {
+                        match followup {
+                            _ if outer_ignoring => {
+                                (ConditionalInterpretation::NotExecuting, Set::default())
+                            }
+                            None => (ConditionalInterpretation::Executing, Set::default()),
+                            Some(condition) => self.evaluate_condition(*condition, names, engine),
+                        }
+                    };
 
+                let top_conditional = &mut self.conditionals[last_idx];
+                top_conditional.sensitivity.extend(sensitivity);
+                // `else ifeq ...` may be followed by further branches, but
+                // nothing may follow a bare `else`
+                top_conditional.seen_else = is_bare_else;
+                top_conditional.branch += 1;
+                top_conditional.interpretation = interpretation;
+                if interpretation == ConditionalInterpretation::Executing {
+                    top_conditional.taken_branch = Some(top_conditional.branch);
+                }
+                self.update_ignoring();
                 Ok(())
             }
-            Conditional::Else(followup) => match followup {
-                None => {
-                    if self.conditionals.len() == 0 {
-                        return Err(ParseErrorKind::UnattachedElse);
-                    }
-                    let last_idx = self.conditionals.len() - 1;
-                    let top_conditional = &mut self.conditionals[last_idx];
-                    if top_conditional.seen_else {
-                        return Err(ParseErrorKind::TooManyElses);
-                    }
-                    top_conditional.interpretation = top_conditional.interpretation.invert();
-                    top_conditional.seen_else = true;
-                    self.update_ignoring();
-                    Ok(())
-                }
-                Some(conditional) => {
-                    unimplemented!("Handling `else` with followup {:?}", conditional)
-                }
-            },
             Conditional::EndIf => {
                 if self.conditionals.len() == 0 {
                     return Err(ParseErrorKind::UnattachedEndIf);
                 }
                 self.conditionals.pop();
+                self.update_ignoring();
+
+                Ok(())
+            }
+            condition => {
+                // Conditionals nested inside a branch that isn't being
+                // interpreted are never evaluated, they only need to be
+                // tracked until their endif
+                let (interpretation, sensitivity) = if self.ignoring {
+                    (ConditionalInterpretation::NotExecuting, Set::default())
+                } else {
+                    self.evaluate_condition(condition, names, engine)
+                };
+                self.conditionals.push(ConditionalState {
+                    interpretation,
+                    seen_else: false,
+                    branch: 0,
+                    taken_branch: if interpretation == ConditionalInterpretation::Executing {
+                        Some(0)
+                    } else {
+                        None
+                    },
+                    sensitivity,
+                });
+                self.update_ignoring();
 
                 Ok(())
             }
-            conditional => unimplemented!("Handling conditional {:?}", conditional),
         }
     }
+
+    /// Evaluate the condition of an `ifeq`, `ifneq`, `ifdef` or `ifndef`,
+    /// returning the variables the result depends on alongside it
+    fn evaluate_condition(
+        &mut self,
+        conditional: Conditional,
+        names: &mut crate::NameCache,
+        engine: &mut crate::Engine,
+    ) -> (ConditionalInterpretation, Set<VariableName>) {
+        let conditional_is_inverted = conditional.is_inverted();
+        let enclosing = self.global_sensitivity();
+        let (holds, sensitivity) = match conditional {
+            Conditional::IfEq(a, b) | Conditional::IfNEq(a, b) => {
+                let a = a.eval_within(names, engine, &enclosing);
+                let b = b.eval_within(names, engine, &enclosing);
+                let sensitivity = a.raw_sensitivity().union(b.raw_sensitivity());
+
+                let a = a.into_string();
+                let b = b.into_string();
+                (a.trim() == b.trim(), sensitivity)
+            }
+            Conditional::IfDef(name) | Conditional::IfNDef(name) => {
+                // #SPC-P-Conditional.ifdef
+                // The name is expanded, but the variable's value is only
+                // checked for emptiness, never expanded
+                let name = name.eval_within(names, engine, &enclosing);
+                let mut sensitivity = name.raw_sensitivity();
+                let name = names.intern_variable_name(name.into_string().trim().into());
+                sensitivity.insert(name);
+
+                let defined = engine
+                    .database
+                    .get_variable(name)
+                    .is_some_and(|variable| !variable.ast().is_empty());
+                (defined, sensitivity)
+            }
+            Conditional::Else(_) | Conditional::EndIf => {
+                unreachable!("{:?} is not a condition", conditional)
+            }
+        };
+        engine.database = engine
+            .database
+            .add_global_sensitivity(sensitivity.iter().cloned());
+
+        let interpretation = if holds != conditional_is_inverted {
+            ConditionalInterpretation::Executing
+        } else {
+            ConditionalInterpretation::NotExecuting
+        };
+        (interpretation, sensitivity)
+    }
 }
 
 /// Parse a conditional line
@@ -0,0 +1,211 @@
+//! A fast path for makefiles that only contain simple rules: lists of
+//! targets and prerequisites without recipes, like the dependency files
+//! written by `gcc -MMD -MP`. These files can be huge, and every line of them
+//! going through the general line parser (and the expansion machinery) is
+//! far too slow. Anything that isn't obviously simple makes the whole file
+//! fall back to the general parser, so the resulting rules are the same
+//! either way.
+
+use super::targets::Action;
+use super::ParserState;
+use crate::evaluated::Block;
+use crate::types::Set;
+use crate::{Engine, NameCache, ParseErrorKind, VariableName};
+use nom::Slice;
+use std::ops::Range;
+
+/// A rule line, as byte ranges of the makefile text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SimpleRule {
+    targets: Vec<Range<usize>>,
+    deps: Vec<Range<usize>>,
+}
+
+/// Words that may start directives (or modify assignments) when they begin a
+/// line. A line starting with one of these always goes through the general
+/// parser.
+const KEYWORDS: &[&str] = &[
+    "define", "else", "endef", "endif", "export", "ifdef", "ifeq", "ifndef", "ifneq", "include",
+    "-include", "sinclude", "load", "-load", "override", "private", "undefine", "unexport",
+    "vpath",
+];
+
+/// Split a makefile into simple rules. Returns `None` if anything in it needs
+/// the general parser: recipes, variables, functions, comments, escapes
+/// other than line continuations, double colons, patterns, directives, and
+/// so on.
+pub(crate) fn scan(text: &str, command_char: char) -> Option<Vec<SimpleRule>> {
+    let bytes = text.as_bytes();
+    let mut rules = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if text[pos..].starts_with(command_char) {
+            // A recipe line, or at least something that might be one
+            return None;
+        }
+
+        let mut words = Vec::new();
+        let mut colon = None;
+        let mut word_start = None;
+        loop {
+            let byte = bytes.get(pos).cloned();
+            let ends_word = match byte {
+                None | Some(b'\n') | Some(b' ') | Some(b'\t') | Some(b':') => true,
+                Some(b'\\') => match bytes.get(pos + 1) {
+                    // A line continuation is just whitespace
+                    Some(b'\n') => true,
+                    _ => return None,
+                },
+                Some(b'$') | Some(b'#') | Some(b'=') | Some(b';') | Some(b'%') | Some(b'\r') => {
+                    return None
+                }
+                Some(_) => false,
+            };
+
+            if ends_word {
+                if let Some(start) = word_start.take() {
+                    words.push(start..pos);
+                }
+            } else if word_start.is_none() {
+                word_start = Some(pos);
+            }
+
+            match byte {
+                None => break,
+                Some(b'\n') => {
+                    pos += 1;
+                    break;
+                }
+                Some(b'\\') => pos += 2,
+                Some(b':') => {
+                    // Only one colon is allowed, and it can't be part of
+                    // `::`, `:=` or a drive specification
+                    match bytes.get(pos + 1) {
+                        Some(b':') | Some(b'/') => return None,
+                        _ => {}
+                    }
+                    if colon.is_some() {
+                        return None;
+                    }
+                    colon = Some(words.len());
+                    pos += 1;
+                }
+                Some(_) => pos += 1,
+            }
+        }
+
+        match colon {
+            // A blank line
+            None if words.is_empty() => {}
+            Some(targets) if targets > 0 => {
+                if KEYWORDS.contains(&&text[words[0].clone()]) {
+                    return None;
+                }
+                let deps = words.split_off(targets);
+                rules.push(SimpleRule {
+                    targets: words,
+                    deps,
+                });
+            }
+            _ => return None,
+        }
+    }
+
+    Some(rules)
+}
+
+/// Add the rules found by [`scan`](fn.scan.html) to the database. `block` must
+/// be the block the makefile text was read into.
+pub(crate) fn process(
+    block: &Block,
+    rules: Vec<SimpleRule>,
+    names: &mut NameCache,
+    engine: &mut Engine,
+    enclosing: Set<VariableName>,
+) -> Result<(), ParseErrorKind> {
+    let span = block.span();
+    let mut parser_state = ParserState::for_block(block, enclosing);
+
+    for rule in rules {
+        let targets = rule
+            .targets
+            .into_iter()
+            .map(|target| span.slice(target).to_new_block())
+            .collect();
+        let deps = rule
+            .deps
+            .into_iter()
+            .map(|dep| span.slice(dep).to_new_block())
+            .collect();
+
+        parser_state.close_rule(names, engine);
+        parser_state.handle_target_action(
+            names,
+            engine,
+            Action::NewRule {
+                targets,
+                deps,
+                secondary_deps: None,
+                double_colon: false,
+                initial_command: None,
+            },
+        )?;
+    }
+
+    parser_state.finish(names, engine)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words<'a>(text: &'a str, ranges: &[Range<usize>]) -> Vec<&'a str> {
+        ranges.iter().map(|range| &text[range.clone()]).collect()
+    }
+
+    #[test]
+    fn gcc_output() {
+        let text =
+            "obj/main.o: src/main.c include/a.h \\\n include/b.h\ninclude/a.h:\n\ninclude/b.h:\n";
+        let rules = scan(text, '\t').unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(words(text, &rules[0].targets), vec!["obj/main.o"]);
+        assert_eq!(
+            words(text, &rules[0].deps),
+            vec!["src/main.c", "include/a.h", "include/b.h"]
+        );
+        assert_eq!(words(text, &rules[1].targets), vec!["include/a.h"]);
+        assert!(rules[1].deps.is_empty());
+        assert_eq!(words(text, &rules[2].targets), vec!["include/b.h"]);
+    }
+
+    #[test]
+    fn multiple_targets() {
+        let text = "a.o b.o : c.h";
+        let rules = scan(text, '\t').unwrap();
+        assert_eq!(words(text, &rules[0].targets), vec!["a.o", "b.o"]);
+        assert_eq!(words(text, &rules[0].deps), vec!["c.h"]);
+    }
+
+    #[test]
+    fn falls_back() {
+        for text in &[
+            "a.o: a.c\n\tcc -c a.c\n",
+            "CFLAGS := -O2\n",
+            "a.o: $(HEADERS)\n",
+            "a.o: a.c # comment\n",
+            "a.o:: a.c\n",
+            "%.o: %.c\n",
+            "a.o: b.o: c.h\n",
+            "a\\ b.o: c.h\n",
+            "a.o: a.c\r\n",
+            "c:/a.o: a.c\n",
+            "export a.o: a.c\n",
+            "a.o a.c\n",
+            ": a.c\n",
+        ] {
+            assert_eq!(scan(text, '\t'), None, "{:?} should fall back", text);
+        }
+    }
+}
@@ -2,6 +2,7 @@
 use crate::ast::AstNode;
 use crate::evaluated::BlockSpan;
 use crate::parsers::ast::parse_ast;
+use crate::parsers::error_out;
 use crate::{Engine, NameCache, ParseErrorKind};
 use nom::IResult;
 
@@ -36,10 +37,74 @@ impl crate::parsers::ParserState {
         match action {
             Action::Include(ast) => self.handle_include(ast, names, engine, false),
             Action::SoftInclude(ast) => self.handle_include(ast, names, engine, true),
+            Action::Export(ast) => self.handle_export(ast, names, engine, true),
+            Action::Unexport(ast) => self.handle_export(ast, names, engine, false),
+            Action::VPath(ast) => self.handle_vpath(ast, names, engine),
             e => unimplemented!("Unimplemented action {:?}", e),
         }
     }
 
+    /// Handle `export` and `unexport` directives. With no variable names,
+    /// these change whether all variables are exported by default.
+    fn handle_export(
+        &mut self,
+        ast: AstNode,
+        names: &mut NameCache,
+        engine: &mut Engine,
+        export: bool,
+    ) -> Result<(), ParseErrorKind> {
+        let contents = ast
+            .eval_within(names, engine, &self.global_sensitivity())
+            .into_string();
+        let mut variables = contents.split_whitespace().peekable();
+        if variables.peek().is_none() {
+            engine.database = engine.database.set_export_all_variables(export);
+            return Ok(());
+        }
+
+        for variable in variables {
+            let name = names.intern_variable_name(variable.into());
+            engine.database = engine.database.set_variable_export(name, export);
+        }
+
+        Ok(())
+    }
+
+    /// Handle `vpath` directives. `vpath pattern directories` adds a search
+    /// path for files matching the pattern, `vpath pattern` removes all the
+    /// search paths for the pattern, and a bare `vpath` removes all of them.
+    fn handle_vpath(
+        &mut self,
+        ast: AstNode,
+        names: &mut NameCache,
+        engine: &mut Engine,
+    ) -> Result<(), ParseErrorKind> {
+        let contents = ast
+            .eval_within(names, engine, &self.global_sensitivity())
+            .into_string();
+        let contents = contents.trim_start();
+        let (pattern, directories) = match contents.find(char::is_whitespace) {
+            Some(idx) => contents.split_at(idx),
+            None => (contents, ""),
+        };
+
+        if pattern.is_empty() {
+            engine.patterned_vpaths.clear();
+        } else if directories.trim().is_empty() {
+            engine.patterned_vpaths.remove(pattern);
+        } else {
+            let directories = crate::split_search_path(directories);
+            // Like GNU make, a pattern with no actual directories is ignored
+            if !directories.is_empty() {
+                engine
+                    .patterned_vpaths
+                    .insert(pattern.to_string(), directories);
+            }
+        }
+
+        Ok(())
+    }
+
     fn handle_include(
         &mut self,
         ast: AstNode,
@@ -48,30 +113,67 @@ impl crate::parsers::ParserState {
         soft: bool,
     ) -> Result<(), ParseErrorKind> {
         use crate::MakefileError;
-        use std::fs::File;
 
         use crate::parsers::file_sequence::{parse_file_seq, FileSeqParseOptions};
 
-        let contents = ast.eval(names, engine);
+        let location = ast.location();
+        let enclosing = self.global_sensitivity();
+        let contents = ast.eval_within(names, engine, &enclosing);
 
         let mut seq_parse_options = FileSeqParseOptions::new(engine.working_directory.clone());
         seq_parse_options.check_ar = false;
 
-        let files = parse_file_seq(contents.span(), seq_parse_options);
-        info!("Include directive will result in include of {} total files", files.len());
+        // Like GNU make, names are expanded as globs (through the engine's
+        // file system), and a glob that matches nothing is kept as it is
+        // TODO: we should surface the full Block form of these somewhere
+        let files: Vec<String> = parse_file_seq(contents.span(), seq_parse_options)
+            .into_iter()
+            .flat_map(|file| {
+                let file = file.into_string();
+                let matches = if file.contains(['*', '?', '[']) {
+                    engine.wildcard(&file)
+                } else {
+                    Vec::new()
+                };
+                if matches.is_empty() {
+                    vec![file]
+                } else {
+                    matches
+                }
+            })
+            .collect();
+        info!(
+            "Include directive will result in include of {} total files",
+            files.len()
+        );
 
         for file in files {
-            let mut path = engine.working_directory.clone();
-            // TODO: we should surface the full Block form of this somewhere
-            let file = file.into_string();
-            path.push(&file);
-            info!("Including file {:?}", path);
-
-            let f = match File::open(&path) {
-                Ok(f) => f,
+            let opened = match engine.resolve_include(&file) {
+                Some((path, directory)) => {
+                    info!("Including file {:?}", path);
+                    engine
+                        .filesystem
+                        .read_to_string(&path)
+                        .map(|contents| (contents, path, directory))
+                }
+                None => Err(std::io::ErrorKind::NotFound.into()),
+            };
+
+            let (contents, path, directory) = match opened {
+                Ok(opened) => opened,
                 Err(e) => {
                     warn!("Failed to include file {:?}", &file);
-                    if soft {
+                    // Missing makefiles might be remade later, see
+                    // Engine::remake_plan
+                    let missing = e.kind() == std::io::ErrorKind::NotFound;
+                    if missing {
+                        engine.push_missing_include(crate::MissingInclude {
+                            name: file.clone(),
+                            soft,
+                            location: location.clone(),
+                        });
+                    }
+                    if soft || (missing && engine.remake_makefiles) {
                         // TODO: Route this through a warnings system
                         continue;
                     } else {
@@ -79,10 +181,26 @@ impl crate::parsers::ParserState {
                     }
                 }
             };
-            let mut br = std::io::BufReader::new(f);
+            // Like GNU make, the makefile is known by the name it was
+            // included as, unless it was found in a search directory
+            let makefile_name = match directory {
+                Some(_) => path.to_string_lossy().into_owned(),
+                None => file.clone(),
+            };
+            engine.push_include(crate::Include {
+                name: file.clone(),
+                path: path.clone(),
+                directory,
+                location: location.clone(),
+            });
 
             engine
-                .read_makefile(names, &mut br, &file)
+                .read_makefile_within(
+                    names,
+                    &mut contents.as_bytes(),
+                    &makefile_name,
+                    enclosing.clone(),
+                )
                 .map_err(|e| match e {
                     MakefileError::IOError(e) => ParseErrorKind::IncludeFailure(e.kind(), file),
                     MakefileError::ParseError(p) => p,
@@ -93,18 +211,31 @@ impl crate::parsers::ParserState {
     }
 }
 
+/// Match a directive keyword. Like GNU make, the keyword has to be a whole
+/// word, so that e.g. `include/config.h: ...` is still a rule
+fn keyword<'a>(
+    i: BlockSpan<'a>,
+    keyword: &'static str,
+) -> IResult<BlockSpan<'a>, BlockSpan<'a>, ParseErrorKind> {
+    let (rest, word) = pe_fix!(i, tag_no_case!(keyword))?;
+    match rest.chars().next() {
+        Some(c) if !c.is_whitespace() => error_out(i, ParseErrorKind::DirectiveExpected),
+        _ => Ok((rest, word)),
+    }
+}
+
 pub(crate) fn parse_line<'a>(i: BlockSpan<'a>) -> IResult<BlockSpan<'a>, Action, ParseErrorKind> {
     pe_fix!(
         i,
         alt!(
-            preceded!(pe_fix!(tag_no_case!("export")), parse_ast) => { Action::Export }     |
-            preceded!(pe_fix!(tag_no_case!("unexport")), parse_ast) => { Action::Unexport } |
-            preceded!(pe_fix!(tag_no_case!("vpath")), parse_ast) => { Action::VPath }       |
-            preceded!(pe_fix!(tag_no_case!("include")), parse_ast) => { Action::Include }   |
-            preceded!(pe_fix!(tag_no_case!("sinclude")), parse_ast) => { Action::SoftInclude }|
-            preceded!(pe_fix!(tag_no_case!("-include")), parse_ast) => { Action::SoftInclude }|
-            preceded!(pe_fix!(tag_no_case!("load")), parse_ast) => { Action::Load }         |
-            preceded!(pe_fix!(tag_no_case!("-load")), parse_ast) => { Action::SoftLoad }
+            preceded!(apply!(keyword, "export"), parse_ast) => { Action::Export }     |
+            preceded!(apply!(keyword, "unexport"), parse_ast) => { Action::Unexport } |
+            preceded!(apply!(keyword, "vpath"), parse_ast) => { Action::VPath }       |
+            preceded!(apply!(keyword, "include"), parse_ast) => { Action::Include }   |
+            preceded!(apply!(keyword, "sinclude"), parse_ast) => { Action::SoftInclude }|
+            preceded!(apply!(keyword, "-include"), parse_ast) => { Action::SoftInclude }|
+            preceded!(apply!(keyword, "load"), parse_ast) => { Action::Load }         |
+            preceded!(apply!(keyword, "-load"), parse_ast) => { Action::SoftLoad }
         )
     )
 }
@@ -12,7 +12,8 @@ pub(crate) struct FileSeqParseOptions {
     pub strip_leading_dotslash: bool,
     /// When true, we will check file names for archive references
     pub check_ar: bool,
-    /// Globs should be expanded
+    /// Globs should be expanded. Not supported here yet; `include` expands
+    /// globs itself through the engine's file system
     pub do_glob: bool,
     /// Only return files that actually exist
     pub exist_only: bool,
@@ -25,7 +26,9 @@ pub(crate) struct FileSeqParseOptions {
 impl Default for FileSeqParseOptions {
     fn default() -> Self {
         // XXX: we should default strip_leading_dotslash, check_ar, and do_glob to true
-        Self::new(std::env::current_dir().expect("Failed to get current directory"))
+        // The process's directory isn't necessarily the engine's working
+        // directory, so an empty path (i.e. relative names) is used here
+        Self::new(PathBuf::new())
     }
 }
 
@@ -18,7 +18,7 @@ macro_rules! pe_complete {
         }
     );
     ($i:expr, $f:expr) => (
-        pe_complete!($i, call!($f));
+        pe_complete!($i, call!($f))
     );
 }
 
@@ -1,5 +1,7 @@
 use crate::ast::AstNode;
 use crate::evaluated::{Block, BlockSpan, ContentReference};
+use crate::skipped_branch::SkippedBranch;
+use crate::types::Set;
 use crate::{Engine, NameCache, ParseErrorKind, Recipe, VariableName};
 use nom::Err as NErr;
 use nom::IResult;
@@ -15,9 +17,10 @@ mod macros;
 pub(crate) mod ast;
 mod comment;
 mod conditional;
+pub(crate) mod dependency_file;
 mod directives;
 mod error_utils;
-mod file_sequence;
+pub(crate) mod file_sequence;
 mod recipe_line;
 pub(crate) mod targets;
 pub(crate) mod variable;
@@ -26,40 +29,51 @@ use self::error_utils::lift_collapsed_span_error;
 use self::recipe_line::recipe_line;
 
 /// Whether this conditional allows or disallows interpretation of the makefile lines currently.
-/// the moral equivalent of this enum (the char *ignoring in the conditional code for GNU make)
-/// has 3 possible states, which is why this is an enum instead of just a bool. I don't know why
-/// they need 3 states.
+/// This is the moral equivalent of the `char *ignoring` in the conditional
+/// code for GNU make. It has 3 states, since once a branch of an
+/// `else ifeq ...` chain has been taken, none of the later ones may be.
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum ConditionalInterpretation {
     /// According to this conditional, lines should be executed
     Executing,
     /// We should definitely ignore everything because the conditional evaluated to false
     NotExecuting,
-}
-
-impl ConditionalInterpretation {
-    fn invert(self) -> Self {
-        match self {
-            ConditionalInterpretation::Executing => ConditionalInterpretation::NotExecuting,
-            ConditionalInterpretation::NotExecuting => ConditionalInterpretation::Executing,
-        }
-    }
+    /// An earlier branch of this conditional was executed, so this one
+    /// (and any following it) is ignored without evaluating its condition
+    AlreadyExecuted,
 }
 
 /// Maintains the state of a conditional (`if{,n}{eq,def}`) while parsing
 #[derive(Clone, Debug, PartialEq)]
 struct ConditionalState {
     interpretation: ConditionalInterpretation,
+    /// A bare `else` was seen, so no more branches may follow
     seen_else: bool,
+    /// Index of the branch currently being parsed. The `if` itself is
+    /// branch 0, and each `else` starts a new branch
+    branch: u32,
+    /// Index of the branch that was interpreted, if any
+    taken_branch: Option<u32>,
+    /// Variables referenced by the conditions evaluated so far. These decide
+    /// which branch is interpreted
+    sensitivity: Set<VariableName>,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct DefineState {
-    /// The variable name we're currently defining
-    var: VariableName,
+    /// The assignment that opened the define. It is applied, with the
+    /// collected body as its value, once the matching `endef` is reached
+    action: variable::VariableAction,
 
     // How deeply nested the defines are
     nesting: u32,
+
+    /// The lines of the body seen so far, joined by their line endings
+    body: Vec<AstNode>,
+
+    /// The line ending of the last line in the body. It's held back since
+    /// the final newline isn't part of the value
+    line_ending: Option<AstNode>,
 }
 
 /// Contains the information that may come out of a rule line. Note that this is
@@ -71,18 +85,23 @@ pub(crate) struct ProtoRule {
     pub targets: Vec<Arc<Block>>,
     /// The inputs for the recipe
     pub deps: Vec<Arc<Block>>,
+    /// Inputs that will only be known after second expansion
+    pub secondary_deps: Vec<crate::SecondaryPrerequisites>,
     /// The recipe to turn `deps` into `targets`
     pub recipe: Recipe,
     /// Extra information about the rule
     pub rule_type: crate::RuleType,
+    /// Global sensitivity of the rule line and the recipe lines
+    pub global_sensitivity: Set<VariableName>,
 }
 
 impl ProtoRule {
-    /// Push a new line into the rule
-    pub(super) fn push_command_line(&mut self, line: AstNode) {
+    /// Push a new line into the rule, under the given global sensitivity
+    pub(super) fn push_command_line(&mut self, line: AstNode, sensitivity: Set<VariableName>) {
         self.recipe.0.push(crate::Command {
             unexpanded_command: line,
-        })
+        });
+        self.global_sensitivity.extend(sensitivity);
     }
 }
 
@@ -101,6 +120,14 @@ pub(crate) struct ParserState {
 
     /// Active define
     current_define: Option<DefineState>,
+
+    /// Global sensitivity of all the text being parsed, e.g. from the
+    /// expansion that produced the text of an `$(eval)`
+    sensitivity: Set<VariableName>,
+
+    /// The skipped branch whose lines are currently being collected, with
+    /// the depth of the conditional it belongs to
+    skipped_branch: Option<(usize, SkippedBranch)>,
 }
 
 impl ParserState {
@@ -110,9 +137,32 @@ impl ParserState {
             ignoring: false,
             current_rule: None,
             current_define: None,
+            sensitivity: Default::default(),
+            skipped_branch: None,
+        }
+    }
+
+    /// Create a parser for the text in `block`, which is globally sensitive
+    /// to everything that its text was sensitive to, and to everything the
+    /// line that produced it (an `include` or `$(eval)`) was sensitive to
+    pub(crate) fn for_block(block: &Block, enclosing: Set<VariableName>) -> ParserState {
+        ParserState {
+            sensitivity: block.raw_sensitivity().union(enclosing),
+            ..ParserState::new()
         }
     }
 
+    /// Get the variables that decide whether the current line is
+    /// interpreted: those of the enclosing conditionals, and of the text
+    /// itself. See #SPC-Sensitivity
+    pub(crate) fn global_sensitivity(&self) -> Set<VariableName> {
+        self.conditionals
+            .iter()
+            .fold(self.sensitivity.clone(), |sensitivity, conditional| {
+                sensitivity.union(conditional.sensitivity.clone())
+            })
+    }
+
     /// Feed a line into the parser
     pub(crate) fn parse_line<'a>(
         &mut self,
@@ -161,17 +211,23 @@ impl ParserState {
 
         if self.currently_processing_rule() {
             // We only check for command lines when we can reasonably expect
-            // that we're currently processing a recipe
+            // that we're currently processing a recipe. Like GNU make, this
+            // is done in false conditional branches too (so a tab-prefixed
+            // `endif` is part of a recipe), but there the line is dropped.
             run_parser!(recipe_line(i, engine.command_char), |line| {
-                // Push the line. This is safe since we only run this parser if
-                // we're currently processing a rule
-                self.push_command_line(line);
+                if self.ignoring {
+                    self.skip_line(i);
+                } else {
+                    // Push the line. This is safe since we only run this
+                    // parser if we're currently processing a rule
+                    self.push_command_line(line);
+                }
                 Ok(())
             });
         } else if self.current_define.is_some() {
             // If we're currently parsing a line inside a define, handle that
             run_parser!(variable::parse_define_line(i), |action| self
-                .handle_define_line(engine, action));
+                .handle_define_line(names, engine, action));
             panic!("Parsing a define line should never fail");
         }
 
@@ -212,22 +268,27 @@ impl ParserState {
         }
 
         run_line_parser!(conditional::parse_line(line.span()), |conditional| self
-            .handle_conditional(conditional, names, engine));
+            .handle_conditional_line(conditional, &line, names, engine));
 
         if self.ignoring {
             // The parse state indicates that we should just ignore this line
-            // We've already collapsed continuations, so just return immediately
+            // Just record it in the skipped branch and return immediately
+            self.skip_line(line_start);
             return Ok((i, ()));
         }
 
+        // Anything parsed while handling this line (included makefiles, or
+        // the text of an `$(eval)`) depends on whatever this line depends on
+        let enclosing = self.global_sensitivity();
+
         run_line_parser!(
-            variable::parse_line(line.span(), names, engine),
-            |variable_action| {
+            variable::parse_line(line.span(), names, engine, &enclosing),
+            |variable_action: variable::VariableAction| {
                 debug!("Matched variable action {:?}", variable_action);
                 // Successful variable assignments close the current rule
                 self.close_rule(names, engine);
 
-                self.handle_global_variable_action(engine, variable_action)
+                self.handle_global_variable_action(names, engine, variable_action)
             }
         );
 
@@ -242,23 +303,40 @@ impl ParserState {
         }
 
         run_line_parser!(
-            targets::parse_line(line.span(), names, engine),
+            targets::parse_line(line.span(), names, engine, &enclosing),
             |target_action| {
                 self.close_rule(names, engine);
 
-                self.handle_target_action(engine, target_action)
+                self.handle_target_action(names, engine, target_action)
             }
         );
 
         Ok((i, ()))
     }
 
+    /// Finish parsing, after the last line has been fed in. This closes any
+    /// open rule and checks that no `define` was left unterminated.
+    pub(crate) fn finish(
+        &mut self,
+        names: &mut NameCache,
+        engine: &mut Engine,
+    ) -> Result<(), ParseErrorKind> {
+        self.close_rule(names, engine);
+        if let Some((_, branch)) = self.skipped_branch.take() {
+            engine.database = engine.database.add_skipped_branch(branch);
+        }
+        match self.current_define.take() {
+            Some(define) => Err(ParseErrorKind::UnterminatedDefine(define.action.location)),
+            None => Ok(()),
+        }
+    }
+
     /// Update the internal ignoring state based on the conditional state and
     /// whether or not we are in a define
     fn update_ignoring(&mut self) {
         // If any conditional is suppressing interpretation, we must be ignoring
         for conditional in &self.conditionals {
-            if conditional.interpretation == ConditionalInterpretation::NotExecuting {
+            if conditional.interpretation != ConditionalInterpretation::Executing {
                 self.ignoring = true;
                 return;
             }
@@ -269,6 +347,16 @@ impl ParserState {
         self.ignoring = false;
     }
 
+    /// Record a line of a false conditional branch in the skipped branch,
+    /// as it was written apart from joined continuations
+    fn skip_line(&mut self, i: BlockSpan) {
+        if let Some((_, branch)) = &mut self.skipped_branch {
+            if let Ok((_, line)) = makefile_line(i, ParserCompliance::GNU, false) {
+                branch.push_line(line);
+            }
+        }
+    }
+
     /// Returns true if there is currently a rule open
     /// That is, if we've seen a target/dep line and nothing has caused us to
     /// dump the rule out to the database yet.
@@ -280,10 +368,11 @@ impl ParserState {
     /// # Panics
     /// Panics if there no rule is currently active
     fn push_command_line(&mut self, line: crate::ast::AstNode) {
+        let sensitivity = self.global_sensitivity();
         self.current_rule
             .as_mut()
             .expect("cannot push command lines while not processing rules")
-            .push_command_line(line);
+            .push_command_line(line, sensitivity);
     }
 
     /// Close out the currently open rule, if there is one
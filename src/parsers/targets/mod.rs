@@ -8,7 +8,8 @@ use crate::parsers::{
     fail_out, lift_collapsed_span_error, makefile_line, makefile_take_until_unquote,
     makefile_whitespace, ProtoRule,
 };
-use crate::{Engine, NameCache, ParseErrorKind};
+use crate::types::Set;
+use crate::{Engine, NameCache, ParseErrorKind, VariableName};
 use nom::IResult;
 use std::sync::Arc;
 
@@ -24,6 +25,8 @@ pub(crate) enum Action {
     NewRule {
         targets: Vec<Arc<Block>>,
         deps: Vec<Arc<Block>>,
+        /// Prerequisites deferred to the second expansion pass
+        secondary_deps: Option<crate::SecondaryPrerequisites>,
         double_colon: bool,
         initial_command: Option<AstNode>,
     },
@@ -39,7 +42,8 @@ pub(crate) enum Action {
 impl crate::parsers::ParserState {
     pub(crate) fn handle_target_action(
         &mut self,
-        _engine: &mut Engine,
+        names: &mut crate::NameCache,
+        engine: &mut Engine,
         action: Action,
     ) -> Result<(), ParseErrorKind> {
         match action {
@@ -49,18 +53,28 @@ impl crate::parsers::ParserState {
             Action::NewRule {
                 targets,
                 deps,
+                secondary_deps,
                 double_colon,
                 initial_command,
             } => {
+                if targets
+                    .iter()
+                    .any(|target| target.into_string() == ".SECONDEXPANSION")
+                {
+                    engine.second_expansion = true;
+                }
+
                 self.current_rule = Some(ProtoRule {
                     targets,
                     deps,
+                    secondary_deps: secondary_deps.into_iter().collect(),
                     recipe: crate::Recipe(Vec::new()),
                     rule_type: if double_colon {
                         crate::RuleType::DoubleColon
                     } else {
                         crate::RuleType::Standard
                     },
+                    global_sensitivity: self.global_sensitivity(),
                 });
 
                 match initial_command {
@@ -68,7 +82,12 @@ impl crate::parsers::ParserState {
                     None => {}
                 }
             }
-            e => unimplemented!("Unimplemented action {:?}", e),
+            Action::TargetVariable {
+                targets,
+                variable_action,
+            } => {
+                self.handle_target_variable_action(names, engine, &targets, variable_action)?;
+            }
         }
         Ok(())
     }
@@ -94,11 +113,13 @@ enum MWordEnd {
 }
 
 /// This function parses a recipe line. It should be handed a reference to the
-/// line, uncollapsed, since we handle collapsing internally
+/// line, uncollapsed, since we handle collapsing internally. `enclosing` is
+/// the global sensitivity of the line.
 pub(crate) fn parse_line<'a>(
     i: BlockSpan<'a>,
     names: &mut NameCache,
     engine: &mut Engine,
+    enclosing: &Set<VariableName>,
 ) -> IResult<BlockSpan<'a>, Action, ParseErrorKind> {
     use nom::{InputIter, Slice};
 
@@ -136,7 +157,7 @@ pub(crate) fn parse_line<'a>(
     macro_rules! expand_segment {
         ($seg:expr) => {{
             let (_, ast) = parse_ast($seg).map_err(|e| lift_collapsed_span_error(e, line_start))?;
-            let v = ast.eval(names, engine);
+            let v = ast.eval_within(names, engine, enclosing);
             v
         }};
     }
@@ -305,7 +326,7 @@ pub(crate) fn parse_line<'a>(
     };
 
     // Try to match against a variable assignment operation
-    match parse_variable_line(post_targets_slice, names, engine) {
+    match parse_variable_line(post_targets_slice, names, engine, enclosing) {
         Ok((_, action)) => {
             return Ok((
                 rest,
@@ -386,9 +407,39 @@ pub(crate) fn parse_line<'a>(
         }
     };
 
+    // This has to be checked before the second expansion is considered, so
+    // that static pattern rules are never mistaken for plain rules
     if second_colon_idx.is_some() {
         // check how p is handled ~ line 1255 and how it's computed above that
-        unimplemented!("Static pattern rules");
+        return fail_out(line_start, ParseErrorKind::StaticPatternRule);
+    }
+
+    // After .SECONDEXPANSION, anything that still contains a reference after
+    // the first expansion must wait until the target is known. GNU make keeps
+    // the whole prerequisite list as text in this case, so we do the same.
+    if engine.second_expansion && pre_semi_slice.chars().any(|c| c == '$') {
+        let (pre_semi_slice, _) = makefile_whitespace(pre_semi_slice).unwrap();
+        let first_expansion = pre_semi_slice.to_new_block();
+        let (_, unexpanded) = parse_ast(first_expansion.span())
+            .map_err(|e| lift_collapsed_span_error(e, line_start))?;
+        debug!(
+            "Deferring prerequisites {:?} to second expansion",
+            first_expansion.into_string()
+        );
+
+        return Ok((
+            rest,
+            Action::NewRule {
+                targets,
+                deps: Vec::new(),
+                secondary_deps: Some(crate::SecondaryPrerequisites {
+                    first_expansion,
+                    unexpanded,
+                }),
+                double_colon,
+                initial_command: command,
+            },
+        ));
     }
 
     debug!("Parsing deps from {:?}", pre_semi_slice.into_string());
@@ -405,6 +456,7 @@ pub(crate) fn parse_line<'a>(
         Action::NewRule {
             targets,
             deps,
+            secondary_deps: None,
             double_colon,
             initial_command: command,
         },
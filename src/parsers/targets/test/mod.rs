@@ -40,13 +40,19 @@ fn tricky_colon_escapes() {
     let d1 = leftover_span(r":", 5, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t1],
             deps: vec![d1],
+            secondary_deps: None,
             double_colon: false,
             initial_command: None,
         }
@@ -61,13 +67,19 @@ fn backslash_in_target() {
     let d1 = leftover_span(";", 9, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t1],
             deps: vec![d1],
+            secondary_deps: None,
             double_colon: false,
             initial_command: None,
         }
@@ -84,13 +96,19 @@ fn backslash_at_end_of_input() {
     let d1 = leftover_span(r"\", 6, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t1],
             deps: vec![d1],
+            secondary_deps: None,
             double_colon: false,
             initial_command: None,
         }
@@ -106,13 +124,19 @@ fn two_backslash_targets() {
     let d = leftover_span(";", 12, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t1, t2],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: false,
             initial_command: None,
         }
@@ -129,13 +153,19 @@ fn semicolon_as_target_with_command() {
     let d = leftover_span("a", 8, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: false,
             initial_command: Some(ast::constant(LocatedString::test_new(1, 10, "b"))),
         }
@@ -152,13 +182,19 @@ fn colon_as_target_with_command() {
     let d = separated_spans(&[(1, 6, "\\"), (1, 9, ";")]);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: false,
             initial_command: Some(ast::constant(LocatedString::test_new(1, 11, "b"))),
         }
@@ -176,13 +212,19 @@ fn mixed_semicolon_target() {
     let d = leftover_span("a", 11, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t1, t2],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: true,
             initial_command: None,
         }
@@ -197,13 +239,19 @@ fn command_simple() {
     let d = leftover_span("a", 5, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: false,
             initial_command: Some(ast::constant(LocatedString::test_new(1, 7, "b"))),
         }
@@ -218,13 +266,19 @@ fn command_dcolon() {
     let d = leftover_span("a", 6, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: true,
             initial_command: Some(ast::constant(LocatedString::test_new(1, 8, "b"))),
         }
@@ -240,13 +294,19 @@ fn escaped_colon_target() {
     let d = leftover_span("a", 7, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: true,
             initial_command: None,
         }
@@ -262,13 +322,19 @@ fn escaped_semicolon_target() {
     let d = leftover_span("a", 8, 1);
     let mut engine = Default::default();
 
-    let (_, action) = assert_ok!(parse_line(block.span(), &mut name_cache, &mut engine));
+    let (_, action) = assert_ok!(parse_line(
+        block.span(),
+        &mut name_cache,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_eq!(
         action,
         Action::NewRule {
             targets: vec![t],
             deps: vec![d],
+            secondary_deps: None,
             double_colon: false,
             initial_command: None,
         }
@@ -104,6 +104,7 @@ fn arb_action() -> impl Strategy<Value = (Action, String, NameCache)> {
                 Action::NewRule {
                     targets,
                     deps,
+                    secondary_deps: None,
                     double_colon: dcolon,
                     initial_command,
                 },
@@ -128,7 +129,7 @@ proptest![
     fn roundtrip_action((action, input, mut names) in arb_action()) {
         let block = create_span(&input);
         let mut engine = Default::default();
-        match parse_line(block.span(), &mut names, &mut engine) {
+        match parse_line(block.span(), &mut names, &mut engine, &Default::default()) {
             Ok((_, action_prime)) => {
                 prop_assert_eq!(action_prime, action);
             }
@@ -28,6 +28,21 @@ macro_rules! variable_set_to (
     }}
 );
 
+/// Run every line of `contents` through a fresh parser, then finish it
+fn read_all(
+    names: &mut crate::NameCache,
+    engine: &mut Engine,
+    contents: &str,
+) -> Result<(), crate::ParseErrorKind> {
+    let block = create_span(contents);
+    let mut parse_state = ParserState::new();
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, names, engine)).0;
+    }
+    parse_state.finish(names, engine)
+}
+
 #[test]
 fn simple_conditional() {
     let block = create_span(
@@ -52,6 +67,7 @@ endif
     let (i, _) = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine));
     // After processing the first line, foo should be interned
     variable_set_to!(names, engine, "foo", "bar");
+    let foo = names.variable_name("foo").unwrap();
 
     // ifeq line
     let (i, _) = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine));
@@ -60,7 +76,10 @@ endif
         parse_state.conditionals,
         vec![super::ConditionalState {
             interpretation: super::ConditionalInterpretation::Executing,
-            seen_else: false
+            seen_else: false,
+            branch: 0,
+            taken_branch: Some(0),
+            sensitivity: std::iter::once(foo).collect(),
         }]
     );
 
@@ -73,8 +92,11 @@ endif
     assert_eq!(
         parse_state.conditionals,
         vec![super::ConditionalState {
-            interpretation: super::ConditionalInterpretation::NotExecuting,
-            seen_else: true
+            interpretation: super::ConditionalInterpretation::AlreadyExecuted,
+            seen_else: true,
+            branch: 1,
+            taken_branch: Some(0),
+            sensitivity: std::iter::once(foo).collect(),
         }]
     );
 
@@ -138,7 +160,7 @@ endef
 
     // End of define line
     let (_i, _) = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine));
-    variable_set_to!(names, engine, "foo", "  bar \\\\\n  baz\n");
+    variable_set_to!(names, engine, "foo", "  bar \\\\\n  baz");
 }
 
 #[test]
@@ -188,3 +210,1104 @@ endif
     // assertions
     variable_set_to!(names, engine, "bar", "qux");
 }
+
+#[test]
+fn exports() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+export CC = gcc
+CFLAGS = -O2
+LDFLAGS = -lm
+HIDDEN = no
+export CFLAGS HIDDEN
+unexport HIDDEN
+all: export TARGET = $@
+all: CFLAGS += -g
+all: ;
+"#,
+    )
+    .unwrap();
+
+    let all = names.file_name("all").expect("Should have all");
+    let environment = engine.recipe_environment(&mut names, all, &[]);
+    let environment: Vec<_> = environment
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(
+        environment,
+        vec![("CC", "gcc"), ("CFLAGS", "-O2 -g"), ("TARGET", "all")]
+    );
+
+    // A bare export exports everything that wasn't explicitly unexported
+    read_all(&mut names, &mut engine, "export\n").unwrap();
+    let environment = engine.recipe_environment(&mut names, all, &[]);
+    assert!(environment.contains_key("LDFLAGS"));
+    assert!(!environment.contains_key("HIDDEN"));
+}
+
+#[test]
+fn command_line_and_override() {
+    use crate::{Diagnostic, Origin};
+
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    assert_ok!(engine.define_command_line_variable(&mut names, "CC=clang"));
+    assert_ok!(engine.define_command_line_variable(&mut names, "CFLAGS := -O0"));
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+CC = gcc
+CC += -m32
+override CFLAGS = -O2
+override CFLAGS += -g
+define CC
+icc
+endef
+all: CC = tcc
+all: override LD = gold
+all: ;
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "CC", "clang");
+    variable_set_to!(names, engine, "CFLAGS", "-O2 -g");
+    let cflags = names.variable_name("CFLAGS").unwrap();
+    assert_eq!(
+        engine.database.get_variable(cflags).unwrap().origin(),
+        Origin::Override
+    );
+
+    let all = names.file_name("all").unwrap();
+    let ld = names.variable_name("LD").unwrap();
+    assert_eq!(
+        engine
+            .database
+            .get_variable_for_target(all, ld)
+            .unwrap()
+            .origin(),
+        Origin::Override
+    );
+
+    let cc = names.variable_name("CC").unwrap();
+    let ignored: Vec<_> = engine
+        .diagnostics()
+        .iter()
+        .filter_map(|diagnostic| match diagnostic {
+            Diagnostic::IgnoredAssignment {
+                variable,
+                target,
+                existing_origin,
+                ..
+            } => Some((*variable, *target, *existing_origin)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        ignored,
+        vec![
+            (cc, None, Origin::Command),
+            (cc, None, Origin::Command),
+            (cc, None, Origin::Command),
+            (cc, Some(all), Origin::Command),
+        ]
+    );
+}
+
+#[test]
+fn environment_import() {
+    use crate::Origin;
+
+    let environment = vec![
+        ("CC", "gcc"),
+        ("HOME", "/home/ci"),
+        ("GREETING", "hello $(HOME)"),
+        ("SHELL", "/bin/zsh"),
+    ];
+    let makefile = r#"
+CC = clang
+"#;
+
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+    engine.import_environment(&mut names, environment.clone(), false);
+    read_all(&mut names, &mut engine, makefile).unwrap();
+
+    variable_set_to!(names, engine, "CC", "clang");
+    variable_set_to!(names, engine, "GREETING", "hello /home/ci");
+    let home = names.variable_name("HOME").unwrap();
+    assert_eq!(
+        engine.database.get_variable(home).unwrap().origin(),
+        Origin::Environment
+    );
+    assert!(names
+        .variable_name("SHELL")
+        .and_then(|shell| engine.database.get_variable(shell))
+        .is_none());
+
+    // With -e semantics, the environment wins
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+    engine.import_environment(&mut names, environment, true);
+    read_all(&mut names, &mut engine, makefile).unwrap();
+
+    variable_set_to!(names, engine, "CC", "gcc");
+    let cc = names.variable_name("CC").unwrap();
+    assert_eq!(
+        engine.database.get_variable(cc).unwrap().origin(),
+        Origin::EnvironmentOverride
+    );
+    assert_eq!(engine.diagnostics().len(), 1);
+}
+
+#[test]
+fn undefine() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    assert_ok!(engine.define_command_line_variable(&mut names, "KEEP=command"));
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+foo = bar
+NAME = foo
+undefine $(NAME)
+undefine KEEP
+all: CFLAGS = -g
+all: override LDFLAGS = -lm
+all: undefine CFLAGS
+all: undefine LDFLAGS
+"#,
+    )
+    .unwrap();
+
+    let foo = names.variable_name("foo").unwrap();
+    assert_eq!(engine.database.variable_origin(foo), None);
+    assert!(engine.database.get_variable(foo).is_none());
+    let tombstone = engine
+        .database
+        .get_tombstone(foo)
+        .expect("Undefined variable should leave a tombstone");
+    assert_eq!(tombstone.origin(), crate::Origin::File);
+    match tombstone.location() {
+        crate::source_location::Location::TestLocation { line, .. } => assert_eq!(*line, 4),
+        l => panic!("Unexpected location {:?}", l),
+    }
+
+    // Command line variables need override to be undefined
+    variable_set_to!(names, engine, "KEEP", "command");
+
+    let all = names.file_name("all").unwrap();
+    let cflags = names.variable_name("CFLAGS").unwrap();
+    let ldflags = names.variable_name("LDFLAGS").unwrap();
+    assert!(engine
+        .database
+        .get_variable_for_target(all, cflags)
+        .is_none());
+    assert!(engine
+        .database
+        .get_tombstone_for_target(all, cflags)
+        .is_some());
+    assert!(engine
+        .database
+        .get_variable_for_target(all, ldflags)
+        .is_some());
+    assert_eq!(engine.diagnostics().len(), 2);
+
+    // Defining the variable again clears the tombstone
+    read_all(&mut names, &mut engine, "foo = baz\n").unwrap();
+    assert!(engine.database.get_tombstone(foo).is_none());
+}
+
+#[test]
+fn target_undefine_in_recipes() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+export CFLAGS := -O2
+export LDFLAGS := -lm
+all: undefine CFLAGS
+all: lib
+	cc $(CFLAGS) $(LDFLAGS)
+lib: undefine LDFLAGS
+lib:
+	ar $(CFLAGS) $(LDFLAGS)
+"#,
+    )
+    .unwrap();
+
+    let all = names.file_name("all").unwrap();
+    let lib = names.file_name("lib").unwrap();
+
+    let environment = engine.recipe_environment(&mut names, all, &[]);
+    assert_eq!(environment.get("CFLAGS"), None);
+    assert_eq!(environment.get("LDFLAGS").map(String::as_str), Some("-lm"));
+
+    let rule = engine.database.get_rule(all).unwrap().clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands[0].script, "cc  -lm");
+
+    // Undefining is inherited by prerequisites, like any target variable
+    let environment = engine.recipe_environment(&mut names, lib, &[all]);
+    assert_eq!(environment.get("CFLAGS"), None);
+    assert_eq!(environment.get("LDFLAGS"), None);
+    let rule = engine.database.get_rule(lib).unwrap().clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[all]);
+    assert_eq!(commands[0].script, "ar  ");
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands[0].script, "ar -O2 ");
+}
+
+#[test]
+fn private_variables() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+private SECRET = hunter2
+PUBLIC = visible
+app: private LDFLAGS = -lapp
+app: CFLAGS = -g
+app: main.o
+	echo $(SECRET) $(PUBLIC)
+main.o:
+	echo $(LDFLAGS) $(CFLAGS)
+"#,
+    )
+    .unwrap();
+
+    let secret = names.variable_name("SECRET").unwrap();
+    assert!(engine.database.get_variable(secret).unwrap().is_private());
+    variable_set_to!(names, engine, "SECRET", "hunter2");
+
+    let app = names.file_name("app").unwrap();
+    let main = names.file_name("main.o").unwrap();
+
+    let rule = engine.database.get_rule(app).unwrap().clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands[0].script, "echo  visible");
+
+    // main.o inherits CFLAGS from app, but not the private LDFLAGS
+    let rule = engine.database.get_rule(main).unwrap().clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[app]);
+    assert_eq!(commands[0].script, "echo  -g");
+
+    // Without app as a parent, nothing is inherited
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands[0].script, "echo  ");
+}
+
+#[test]
+fn variable_flavors() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+X = one
+SIMPLE := $(X)
+SIMPLE += $(X)
+RECURSIVE = $(X)
+RECURSIVE += $(X)
+POSIX :::= $(X) $$
+POSIX += $(X)
+X = two
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "SIMPLE", "one one");
+    variable_set_to!(names, engine, "RECURSIVE", "two two");
+    variable_set_to!(names, engine, "POSIX", "one $ two");
+
+    let simple = names.variable_name("SIMPLE").unwrap();
+    let posix = names.variable_name("POSIX").unwrap();
+    assert_eq!(
+        engine.database.get_variable(simple).unwrap().flavor(),
+        crate::Flavor::Simple
+    );
+    assert_eq!(
+        engine.database.get_variable(posix).unwrap().flavor(),
+        crate::Flavor::Recursive
+    );
+}
+
+#[test]
+fn conditional_assignment() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    engine.import_environment(&mut names, vec![("FROM_ENV", "env")], false);
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+DEFINED = first
+DEFINED ?= second
+FROM_ENV ?= file
+NEW ?= default $(DEFINED)
+define BLOCK ?=
+block
+endef
+define DEFINED ?=
+ignored
+endef
+all: NEW ?= target
+all: LOCAL ?= local
+all: ;
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "DEFINED", "first");
+    variable_set_to!(names, engine, "FROM_ENV", "env");
+    variable_set_to!(names, engine, "NEW", "default first");
+    variable_set_to!(names, engine, "BLOCK", "block");
+
+    // The value of a ?= assignment depends on the variable having been undefined
+    let new = names.variable_name("NEW").unwrap();
+    let defined = names.variable_name("DEFINED").unwrap();
+    let value = engine
+        .database
+        .get_variable(new)
+        .unwrap()
+        .ast()
+        .clone()
+        .eval(&mut names, &mut engine);
+    let sensitivity: Vec<_> = value.sensitivity().cloned().collect();
+    assert!(sensitivity.contains(&new));
+    assert!(sensitivity.contains(&defined));
+    assert_eq!(
+        engine.database.get_variable(new).unwrap().flavor(),
+        crate::Flavor::Recursive
+    );
+
+    let all = names.file_name("all").unwrap();
+    let local = names.variable_name("LOCAL").unwrap();
+    assert!(engine.database.get_variable_for_target(all, new).is_none());
+    assert!(engine
+        .database
+        .get_variable_for_target(all, local)
+        .is_some());
+}
+
+#[test]
+fn define_operators() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    engine
+        .define_command_line_variable(&mut names, "FIXED=command")
+        .unwrap();
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+X = one
+define SIMPLE :=
+$(X) $$(X)
+endef
+define RECURSIVE =
+$(X)
+  second
+endef
+RECURSIVE += appended
+define RECURSIVE +=
+more
+endef
+override define FIXED
+from file
+endef
+export define EXPORTED
+exported
+endef
+define EMPTY
+endef
+define POSIX :::=
+$(X) $$(X)
+endef
+PLAIN_POSIX :::= $(X) $$(X)
+define SHELL !=
+echo $(X)
+endef
+PLAIN_SHELL != echo $(X)
+X = two
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "SIMPLE", "one $(X)");
+    variable_set_to!(names, engine, "RECURSIVE", "two\n  second appended more");
+    variable_set_to!(names, engine, "FIXED", "from file");
+    variable_set_to!(names, engine, "EXPORTED", "exported");
+    variable_set_to!(names, engine, "EMPTY", "");
+
+    let simple = names.variable_name("SIMPLE").unwrap();
+    let fixed = names.variable_name("FIXED").unwrap();
+    let exported = names.variable_name("EXPORTED").unwrap();
+    assert_eq!(
+        engine.database.get_variable(simple).unwrap().flavor(),
+        crate::Flavor::Simple
+    );
+    assert_eq!(
+        engine.database.get_variable(fixed).unwrap().origin(),
+        crate::Origin::Override
+    );
+    assert!(engine.database.is_exported(exported));
+
+    // `:::=` expands the body when it's defined (keeping the escaped `$`),
+    // but the variable is recursive
+    variable_set_to!(names, engine, "POSIX", "one $(X)");
+    variable_set_to!(names, engine, "PLAIN_POSIX", "one $(X)");
+    let posix = names.variable_name("POSIX").unwrap();
+    assert_eq!(
+        engine.database.get_variable(posix).unwrap().flavor(),
+        crate::Flavor::Recursive
+    );
+
+    // A `!=` define behaves like the single line form
+    let shell = names.variable_name("SHELL").unwrap();
+    let plain_shell = names.variable_name("PLAIN_SHELL").unwrap();
+    assert_eq!(
+        engine.database.get_variable(shell).unwrap().flavor(),
+        crate::Flavor::Shell
+    );
+    assert_eq!(
+        engine.database.get_variable(plain_shell).unwrap().flavor(),
+        crate::Flavor::Shell
+    );
+    variable_set_to!(names, engine, "SHELL", "echo two");
+    variable_set_to!(names, engine, "PLAIN_SHELL", "echo two");
+}
+
+#[test]
+fn unterminated_define() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    let result = read_all(
+        &mut names,
+        &mut engine,
+        "FOO = bar\ndefine BODY\nnever ended\n",
+    );
+    match result {
+        Err(crate::ParseErrorKind::UnterminatedDefine(location)) => {
+            assert_eq!(
+                location,
+                crate::source_location::Location::TestLocation {
+                    line: 2,
+                    character: 1
+                }
+            );
+        }
+        other => panic!("Expected an unterminated define, got {:?}", other),
+    }
+}
+
+#[test]
+fn target_specific_define() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    let block = create_span(
+        "all: define FOO
+body
+endef
+",
+    );
+    let mut parse_state = ParserState::new();
+    assert_err_contains!(
+        parse_state
+            .parse_line(block.span(), &mut names, &mut engine)
+            .unwrap_err(),
+        crate::ParseErrorKind::TargetSpecificDefine
+    );
+    assert!(engine
+        .database
+        .get_variable(names.variable_name("FOO").unwrap())
+        .is_none());
+}
+
+#[test]
+fn ifdef_conditionals() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+EMPTY =
+VALUE = x
+REF = $(EMPTY)
+NAME = VALUE
+ifdef VALUE
+A = yes
+else
+A = no
+endif
+ifdef EMPTY
+B = yes
+else
+B = no
+endif
+ifdef REF
+C = yes
+endif
+ifndef UNSET
+D = yes
+endif
+ifdef $(NAME)
+E = yes
+endif
+ifdef UNSET
+ifdef VALUE
+F = nested
+endif
+else
+F = outer
+endif
+G = after
+APPENDED =
+APPENDED +=
+APPENDED +=
+ifdef APPENDED
+H = yes
+else
+H = no
+endif
+LEADING =
+LEADING += a
+MAYBE ?=
+ifdef MAYBE
+I = yes
+else
+I = no
+endif
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "A", "yes");
+    variable_set_to!(names, engine, "B", "no");
+    // The value isn't expanded, so a reference to an empty variable counts
+    variable_set_to!(names, engine, "C", "yes");
+    variable_set_to!(names, engine, "D", "yes");
+    variable_set_to!(names, engine, "E", "yes");
+    variable_set_to!(names, engine, "F", "outer");
+    variable_set_to!(names, engine, "G", "after");
+    // Appending nothing to an empty value leaves it empty
+    variable_set_to!(names, engine, "H", "no");
+    variable_set_to!(names, engine, "APPENDED", "");
+    variable_set_to!(names, engine, "LEADING", "a");
+    variable_set_to!(names, engine, "I", "no");
+
+    let sensitivity: Vec<_> = engine.database.global_sensitivity().cloned().collect();
+    for name in &["VALUE", "EMPTY", "REF", "UNSET", "NAME"] {
+        let name = names.variable_name(name).unwrap();
+        assert!(sensitivity.contains(&name));
+    }
+}
+
+#[test]
+fn else_chains() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+X = b
+ifeq ($(X),a)
+A = first
+else ifeq ($(X),b)
+A = second
+else ifeq ($(X),b)
+A = third
+else
+A = fourth
+endif
+ifdef UNSET
+B = first
+else ifndef X
+B = second
+else
+B = fallback
+endif
+ifeq ($(X),b)
+C = first
+else ifdef X
+C = second
+endif
+ifdef UNSET
+ifeq (1,1)
+D = nested
+else ifeq (2,2)
+D = nested else
+endif
+else ifdef X
+D = outer
+endif
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "A", "second");
+    variable_set_to!(names, engine, "B", "fallback");
+    variable_set_to!(names, engine, "C", "first");
+    variable_set_to!(names, engine, "D", "outer");
+}
+
+#[test]
+fn else_chain_errors() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    let block = create_span("ifdef X\nelse\nelse ifdef Y\nendif\n");
+    let mut parse_state = ParserState::new();
+    let i = assert_ok!(parse_state.parse_line(block.span(), &mut names, &mut engine)).0;
+    let i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    assert_err_contains!(
+        parse_state
+            .parse_line(i, &mut names, &mut engine)
+            .unwrap_err(),
+        crate::ParseErrorKind::TooManyElses
+    );
+
+    let block = create_span("else ifdef Y\n");
+    let mut parse_state = ParserState::new();
+    assert_err_contains!(
+        parse_state
+            .parse_line(block.span(), &mut names, &mut engine)
+            .unwrap_err(),
+        crate::ParseErrorKind::UnattachedElse
+    );
+
+    // Any number of chained branches may come before the final else
+    read_all(
+        &mut names,
+        &mut engine,
+        "ifdef X\nelse ifdef Y\nelse ifdef Z\nelse\nendif\n",
+    )
+    .unwrap();
+}
+
+#[test]
+fn conditional_global_sensitivity() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+MODE = debug
+UNCONDITIONAL = x
+ifeq ($(MODE),release)
+FLAGS = -O2
+else ifdef USE_CC
+FLAGS = -g
+else
+FLAGS = -O0
+app: main.c
+	cc $(FLAGS) -o $@ $<
+endif
+FLAGS += -Wall
+lib: lib.c
+ifndef USE_CC
+	cc -c $<
+endif
+ifdef USE_CC
+	$(CC) -c $<
+endif
+EVAL_NAME = EVALED
+$(eval $(EVAL_NAME) = 1)
+"#,
+    )
+    .unwrap();
+
+    let sensitivity_of = |names: &crate::NameCache, variable: &str| {
+        let variable = names.variable_name(variable).unwrap();
+        engine
+            .database
+            .get_variable(variable)
+            .unwrap()
+            .global_sensitivity()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let mode = names.variable_name("MODE").unwrap();
+    let use_cc = names.variable_name("USE_CC").unwrap();
+
+    assert!(sensitivity_of(&names, "UNCONDITIONAL").is_empty());
+    // Text produced for an $(eval) depends on what it was expanded from
+    let eval_name = names.variable_name("EVAL_NAME").unwrap();
+    assert_eq!(sensitivity_of(&names, "EVALED"), vec![eval_name]);
+    let flags = sensitivity_of(&names, "FLAGS");
+    assert!(flags.contains(&mode));
+    assert!(flags.contains(&use_cc));
+
+    let rule_sensitivity = |names: &crate::NameCache, target: &str| {
+        let target = names.file_name(target).unwrap();
+        engine
+            .database
+            .get_rule(target)
+            .unwrap()
+            .global_sensitivity()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let app = rule_sensitivity(&names, "app");
+    assert!(app.contains(&mode));
+    assert!(app.contains(&use_cc));
+    // The rule itself is unconditional, but its recipe lines aren't
+    assert_eq!(rule_sensitivity(&names, "lib"), vec![use_cc]);
+    // Only the recipe line from the true branch is kept
+    let lib = names.file_name("lib").unwrap();
+    assert_eq!(engine.database.get_rule(lib).unwrap().recipe().0.len(), 1);
+}
+
+#[test]
+fn nested_text_sensitivity() {
+    use crate::MemoryFileSystem;
+    use std::sync::Arc;
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.add_file("/project/extra.mk", "FROM_INCLUDE = 1\nextra:\n");
+    let mut engine = Engine {
+        working_directory: "/project".into(),
+        search_default_include_directories: false,
+        filesystem: Arc::new(filesystem),
+        ..Default::default()
+    };
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+ifndef X
+include extra.mk
+$(eval FROM_EVAL = 1)
+endif
+AFTER = 1
+"#,
+    )
+    .unwrap();
+
+    let x = names.variable_name("X").unwrap();
+    let sensitivity_of = |names: &crate::NameCache, variable: &str| {
+        let variable = names.variable_name(variable).unwrap();
+        engine
+            .database
+            .get_variable(variable)
+            .unwrap()
+            .global_sensitivity()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sensitivity_of(&names, "FROM_INCLUDE"), vec![x]);
+    assert_eq!(sensitivity_of(&names, "FROM_EVAL"), vec![x]);
+    assert!(sensitivity_of(&names, "AFTER").is_empty());
+
+    let extra = names.file_name("extra").unwrap();
+    let rule_sensitivity: Vec<_> = engine
+        .database
+        .get_rule(extra)
+        .unwrap()
+        .global_sensitivity()
+        .cloned()
+        .collect();
+    assert_eq!(rule_sensitivity, vec![x]);
+}
+
+#[test]
+fn recipe_lines_in_false_branches() {
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    // Inside a rule, a tab-prefixed `endif` is a recipe line, even in a
+    // branch that is skipped
+    read_all(
+        &mut names,
+        &mut engine,
+        "a:\n\techo\nifdef UNDEF\n\tendif\nendif\nAFTER = 1\n",
+    )
+    .unwrap();
+
+    let a = names.file_name("a").unwrap();
+    assert_eq!(engine.database.get_rule(a).unwrap().recipe().0.len(), 1);
+    variable_set_to!(names, engine, "AFTER", "1");
+    let branches: Vec<_> = engine.database.skipped_branches().collect();
+    assert_eq!(branches.len(), 1);
+    assert_eq!(branches[0].lines().len(), 1);
+    assert_eq!(branches[0].lines()[0].into_string(), "\tendif");
+}
+
+#[test]
+fn skipped_branches() {
+    use crate::SkipReason;
+
+    let mut engine: Engine = Default::default();
+    let mut names = Default::default();
+
+    read_all(
+        &mut names,
+        &mut engine,
+        r#"
+X = b
+ifeq ($(X),a)
+A = first
+ifdef X
+NESTED = yes
+endif
+else ifeq ($(X),b)
+A = second
+else
+  A = third
+endif
+app: main.c
+	cc -c main.c
+ifdef UNSET
+	cc -g main.c
+endif
+"#,
+    )
+    .unwrap();
+
+    variable_set_to!(names, engine, "A", "second");
+    assert!(names.variable_name("NESTED").is_none());
+    let app = names.file_name("app").unwrap();
+    assert_eq!(engine.database.get_rule(app).unwrap().recipe().0.len(), 1);
+
+    let branches: Vec<_> = engine.database.skipped_branches().collect();
+    assert_eq!(branches.len(), 3);
+
+    assert_eq!(branches[0].directive().into_string(), "ifeq ($(X),a)");
+    assert_eq!(branches[0].reason(), SkipReason::ConditionFalse);
+    assert_eq!(
+        branches[0].location(),
+        crate::source_location::Location::TestLocation {
+            line: 3,
+            character: 1
+        }
+    );
+    let lines: Vec<_> = branches[0]
+        .lines()
+        .iter()
+        .map(|line| line.into_string())
+        .collect();
+    assert_eq!(lines, vec!["A = first", "ifdef X", "NESTED = yes", "endif"]);
+
+    assert_eq!(branches[1].directive().into_string(), "else");
+    assert_eq!(branches[1].reason(), SkipReason::EarlierBranchTaken);
+    assert_eq!(branches[1].lines().len(), 1);
+    assert_eq!(branches[1].lines()[0].into_string(), "  A = third");
+
+    assert_eq!(branches[2].directive().into_string(), "ifdef UNSET");
+    assert_eq!(branches[2].lines()[0].into_string(), "\tcc -g main.c");
+}
+
+#[test]
+fn dependency_file_fast_path() {
+    use crate::parsers::dependency_file;
+    use crate::source_location::Location;
+
+    let contents = "obj/main.o obj/main.d: src/main.c include/a.h \\
+  include/b.h
+obj/util.o: src/util.c \\
+ include/a.h
+
+include/a.h:
+include/b.h:
+obj/main.o: extra.h
+";
+    let block = create_span(contents);
+    let mut names = Default::default();
+
+    let mut general: Engine = Default::default();
+    general.process_block(&mut names, &block).unwrap();
+
+    let rules = dependency_file::scan(contents, '\t').expect("should be a simple file");
+    let mut fast: Engine = Default::default();
+    dependency_file::process(&block, rules, &mut names, &mut fast, Default::default()).unwrap();
+
+    let sorted_rules = |engine: &Engine| {
+        let mut rules: Vec<crate::Rule> = engine.database.rules().cloned().collect();
+        rules.sort_by_key(|rule| rule.target_fname());
+        rules
+    };
+    let general_rules = sorted_rules(&general);
+    assert_eq!(general_rules.len(), 5);
+    assert_eq!(general_rules, sorted_rules(&fast));
+
+    // Locations point into the original text, past continuations
+    let main_o = names.file_name("obj/main.o").unwrap();
+    let rule = fast.database.get_rule(main_o).unwrap();
+    assert_eq!(rule.location(), Location::test_location(1, 1));
+    let deps: Vec<(String, Option<Location>)> = rule
+        .dependencies()
+        .iter()
+        .map(|dep| (dep.into_string(), dep.span().location()))
+        .collect();
+    assert_eq!(
+        deps,
+        vec![
+            ("src/main.c".into(), Some(Location::test_location(1, 24))),
+            ("include/a.h".into(), Some(Location::test_location(1, 35))),
+            ("include/b.h".into(), Some(Location::test_location(2, 3))),
+            ("extra.h".into(), Some(Location::test_location(8, 13))),
+        ]
+    );
+
+    let default_goal = names.variable_name(".DEFAULT_GOAL").unwrap();
+    assert_eq!(
+        fast.database
+            .get_variable(default_goal)
+            .unwrap()
+            .ast()
+            .location(),
+        general
+            .database
+            .get_variable(default_goal)
+            .unwrap()
+            .ast()
+            .location()
+    );
+}
+
+#[test]
+fn dependency_files_match_the_general_parser() {
+    use crate::parsers::dependency_file;
+    use crate::source_location::Location;
+
+    // Each rule's target, recipe length and prerequisites, with locations,
+    // in definition order
+    type RuleLocations = Vec<(String, Location, usize, Vec<(String, Option<Location>)>)>;
+    fn rule_locations(engine: &Engine) -> RuleLocations {
+        engine
+            .database
+            .rules()
+            .map(|rule| {
+                let deps = rule
+                    .dependencies()
+                    .iter()
+                    .map(|dep| (dep.into_string(), dep.span().location()))
+                    .collect();
+                (
+                    rule.target().into_string(),
+                    rule.location(),
+                    rule.recipe().commands().len(),
+                    deps,
+                )
+            })
+            .collect()
+    }
+
+    let files = [
+        // `gcc -MMD -MP`
+        "build/a.o: src/a.c src/a.h \\\n  /usr/include/stdio.h \\\n  src/b.h\n\nsrc/a.h:\n\n/usr/include/stdio.h:\n\nsrc/b.h:\n",
+        // Several targets, repeated targets, odd spacing and no final newline
+        "x.o  y.o :z.h   w.h\n\n  \nx.o: \\\n\\\n v.h\ny.o:",
+        // A long target name puts every prerequisite on a continuation line
+        "build/long/target.o: \\\n src/long/target.c \\\n src/long/target.h\n",
+        // Only phony rules
+        "a.h:\nb.h:\n",
+    ];
+    for contents in files.iter() {
+        let block = create_span(contents);
+        let mut names = Default::default();
+
+        let mut general: Engine = Default::default();
+        general.process_block(&mut names, &block).unwrap();
+
+        let rules = dependency_file::scan(contents, '\t').expect("should be a simple file");
+        let mut fast: Engine = Default::default();
+        dependency_file::process(&block, rules, &mut names, &mut fast, Default::default()).unwrap();
+
+        let expected = rule_locations(&general);
+        assert!(!expected.is_empty());
+        assert_eq!(rule_locations(&fast), expected, "reading {:?}", contents);
+        assert_eq!(
+            fast.database.default_goal(),
+            general.database.default_goal()
+        );
+    }
+}
+
+#[test]
+fn dependency_files_read_from_disk() {
+    // The same rules, read through the fast path and (because of the
+    // trailing assignment) through the general parser
+    let contents = "obj/main.o: src/main.c \\\n include/a.h\ninclude/a.h:\n";
+    let mut names = Default::default();
+    let mut fast: Engine = Default::default();
+    fast.read_makefile(&mut names, &mut contents.as_bytes(), "main.d")
+        .unwrap();
+    let mut general: Engine = Default::default();
+    let with_assignment = format!("{}X = 1\n", contents);
+    general
+        .read_makefile(&mut names, &mut with_assignment.as_bytes(), "main.d")
+        .unwrap();
+
+    let fast_rules: Vec<_> = fast.database.rules().collect();
+    let general_rules: Vec<_> = general.database.rules().collect();
+    assert_eq!(fast_rules.len(), 2);
+    assert_eq!(fast_rules, general_rules);
+}
+
+#[test]
+fn vpath_directives() {
+    use std::path::PathBuf;
+
+    let mut names = Default::default();
+    let mut engine: Engine = Default::default();
+    assert_ok!(read_all(
+        &mut names,
+        &mut engine,
+        "SRC := src
+vpath %.c $(SRC):lib/ other
+vpath %.h include
+vpath %.c generated
+vpath %.o
+",
+    ));
+
+    let paths = |dirs: &[&str]| dirs.iter().map(PathBuf::from).collect::<Vec<_>>();
+    assert_eq!(
+        engine.patterned_vpaths.matches("main.c"),
+        vec![paths(&["src", "lib", "other"]), paths(&["generated"])]
+    );
+    assert_eq!(
+        engine.patterned_vpaths.matches("a.h"),
+        vec![paths(&["include"])]
+    );
+    assert!(engine.patterned_vpaths.matches("main.o").is_empty());
+
+    assert_ok!(read_all(&mut names, &mut engine, "vpath %.c\n"));
+    assert!(engine.patterned_vpaths.matches("main.c").is_empty());
+    assert_eq!(engine.patterned_vpaths.matches("a.h").len(), 1);
+
+    assert_ok!(read_all(&mut names, &mut engine, "vpath\n"));
+    assert!(engine.patterned_vpaths.is_empty());
+}
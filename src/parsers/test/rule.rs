@@ -1,6 +1,7 @@
 //! Tests the rule parser
 use super::create_span;
 use crate::parsers::ParserState;
+use crate::source_location::Location;
 use crate::Engine;
 
 #[test]
@@ -113,3 +114,446 @@ a: c
     assert!(rule.dep_names.contains(&dep1_name));
     assert!(rule.dep_names.contains(&dep2_name));
 }
+
+#[test]
+fn override_recipe() {
+    crate::test::setup();
+    let block = create_span(
+        r#"
+a: b
+	@echo old
+
+a: c
+	@echo new
+"#,
+    );
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let target_name = names.intern_file_name("a".into());
+    let dep1_name = names.intern_file_name("b".into());
+    let dep2_name = names.intern_file_name("c".into());
+
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let rule = engine
+        .database
+        .get_rule(target_name)
+        .expect("Should have generated rule");
+    // Only the recipe is replaced; prerequisites accumulate
+    assert!(rule.deps.len() == 2);
+    assert!(rule.dep_names.len() == 2);
+    assert!(rule.dep_names.contains(&dep1_name));
+    assert!(rule.dep_names.contains(&dep2_name));
+
+    assert_eq!(engine.diagnostics().len(), 1);
+    match &engine.diagnostics()[0] {
+        crate::Diagnostic::OverridingRecipe {
+            target,
+            new_location,
+            old_location,
+            old_rule,
+        } => {
+            assert_eq!(*target, target_name);
+            assert_eq!(*new_location, Location::test_location(5, 1));
+            assert_eq!(*old_location, Location::test_location(2, 1));
+            assert!(old_rule.dep_names.contains(&dep1_name));
+        }
+        d => panic!("Unexpected diagnostic {:?}", d),
+    }
+}
+
+#[test]
+fn recipe_after_deps_only() {
+    crate::test::setup();
+    let block = create_span(
+        r#"
+a: b
+
+a: c
+	@echo new
+"#,
+    );
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let target_name = names.intern_file_name("a".into());
+
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let rule = engine
+        .database
+        .get_rule(target_name)
+        .expect("Should have generated rule");
+    assert!(rule.deps.len() == 2);
+    assert!(engine.diagnostics().is_empty());
+}
+
+#[test]
+fn second_expansion() {
+    use crate::evaluated::EvaluatedNode;
+
+    crate::test::setup();
+    let block = create_span(
+        r#"
+.SECONDEXPANSION:
+foo/bar.o: bar.c
+foo/bar.o: $$(dir $$@)config.h $$<.d
+"#,
+    );
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+    assert!(engine.second_expansion);
+
+    let target_name = names
+        .file_name("foo/bar.o")
+        .expect("Should have target name");
+    let rule = engine
+        .database
+        .get_rule(target_name)
+        .expect("Should have generated rule");
+    assert_eq!(rule.deps.len(), 1);
+    assert_eq!(rule.secondary_dependencies().len(), 1);
+    assert_eq!(
+        rule.secondary_dependencies()[0]
+            .first_expansion()
+            .into_string(),
+        "$(dir $@)config.h $<.d"
+    );
+
+    engine.expand_secondary_prerequisites(&mut names);
+
+    let rule = engine
+        .database
+        .get_rule(target_name)
+        .expect("Should still have the rule");
+    let deps: Vec<String> = rule
+        .dependencies()
+        .iter()
+        .map(|d| d.into_string())
+        .collect();
+    assert_eq!(deps, vec!["bar.c", "foo/config.h", "bar.c.d"]);
+    assert!(rule.secondary_dependencies().is_empty());
+    assert!(rule
+        .dep_names
+        .contains(&names.file_name("foo/config.h").unwrap()));
+
+    // The expanded dependency should remember both expansion stages
+    match rule.dependencies()[1].content().next().unwrap().node() {
+        EvaluatedNode::SecondExpansion(node) => {
+            assert_eq!(
+                node.first_expansion().into_string(),
+                "$(dir $@)config.h $<.d"
+            );
+            assert_eq!(node.value().into_string(), "foo/config.h bar.c.d");
+        }
+        node => panic!("Unexpected node {:?}", node),
+    }
+
+    // Automatic variables should not leak out of the second expansion
+    assert!(names
+        .variable_name("@")
+        .and_then(|v| engine.database.get_variable(v))
+        .is_none());
+}
+
+#[test]
+fn special_targets() {
+    crate::test::setup();
+    let block = create_span(
+        r#"
+.PHONY: all clean
+.DELETE_ON_ERROR:
+.SECONDARY:
+all: foo
+"#,
+    );
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let all = names.file_name("all").expect("Should have all");
+    let clean = names.file_name("clean").expect("Should have clean");
+    let foo = names.file_name("foo").expect("Should have foo");
+
+    assert!(engine.database.is_phony(all));
+    assert!(engine.database.is_phony(clean));
+    assert!(!engine.database.is_phony(foo));
+    assert!(names
+        .file_name(".PHONY")
+        .and_then(|phony| engine.database.get_rule(phony))
+        .is_none());
+    assert!(engine.database.get_rule(all).is_some());
+
+    assert!(engine.database.flags().delete_on_error);
+    assert!(engine.database.flags().all_secondary);
+    assert!(engine.database.is_secondary(foo));
+    assert!(!engine.database.is_precious(all));
+}
+
+#[test]
+fn default_goal() {
+    crate::test::setup();
+    let mut names = crate::NameCache::default();
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+
+    let block = create_span(
+        r#"
+.PHONY: all
+%.o: %.c
+.c.o:
+all: foo
+foo:
+"#,
+    );
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let all = names.file_name("all").expect("Should have all");
+    assert_eq!(engine.database.default_goal(), Some(all));
+
+    // An explicit assignment wins over the first target
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let block = create_span(
+        r#"
+.DEFAULT_GOAL = foo
+all:
+foo:
+"#,
+    );
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let foo = names.file_name("foo").expect("Should have foo");
+    assert_eq!(engine.database.default_goal(), Some(foo));
+
+    // The assignment is expanded, and one that expands to nothing leaves the
+    // first target to become the default goal
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let block = create_span(
+        r#"
+.DEFAULT_GOAL = $(EMPTY)
+first:
+GOAL = foo
+.DEFAULT_GOAL = $(GOAL)
+"#,
+    );
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+        if i.into_string().starts_with(".DEFAULT_GOAL = $(GOAL)") {
+            let first = names.file_name("first").expect("Should have first");
+            assert_eq!(engine.database.default_goal(), Some(first));
+        }
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+    assert_eq!(engine.database.default_goal(), Some(foo));
+}
+
+#[test]
+fn recipe_prefix_and_oneshell() {
+    crate::test::setup();
+    let mut names = crate::NameCache::default();
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+
+    let block = create_span(
+        r#"
+.RECIPEPREFIX = >
+foo: bar
+>@echo $@
+>-cd $<
+.RECIPEPREFIX =
+baz:
+	@echo baz
+	+echo done
+"#,
+    );
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+    assert_eq!(engine.command_char, '\t');
+
+    let foo = names.file_name("foo").expect("Should have foo");
+    let rule = engine
+        .database
+        .get_rule(foo)
+        .expect("Should have foo rule")
+        .clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[0].script, "echo foo");
+    assert!(commands[0].silent);
+    assert_eq!(commands[1].script, "cd bar");
+    assert!(commands[1].ignore_errors);
+
+    // With .ONESHELL, only the first line's prefix characters are interpreted
+    let block = create_span(".ONESHELL:\n");
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    let baz = names.file_name("baz").expect("Should have baz");
+    let rule = engine
+        .database
+        .get_rule(baz)
+        .expect("Should have baz rule")
+        .clone();
+    let commands = engine.expand_recipe(&mut names, &rule, &[]);
+    assert_eq!(commands.len(), 1);
+    assert_eq!(commands[0].script, "echo baz\n+echo done");
+    assert!(commands[0].silent);
+    assert!(!commands[0].always_run);
+}
+
+#[test]
+fn recipe_prefix_from_any_assignment() {
+    crate::test::setup();
+    let mut names = crate::NameCache::default();
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+
+    let mut read = |names: &mut crate::NameCache, engine: &mut Engine, text: &str| {
+        let block = create_span(text);
+        let mut i = block.span();
+        while i.len() > 0 {
+            i = assert_ok!(parse_state.parse_line(i, names, engine)).0;
+        }
+        engine.command_char
+    };
+
+    assert_eq!(
+        read(&mut names, &mut engine, "define .RECIPEPREFIX\n>\nendef\n"),
+        '>'
+    );
+    assert_eq!(
+        read(&mut names, &mut engine, "undefine .RECIPEPREFIX\n"),
+        '\t'
+    );
+    assert_eq!(
+        read(&mut names, &mut engine, "$(eval .RECIPEPREFIX = @)\n"),
+        '@'
+    );
+    assert_eq!(
+        read(&mut names, &mut engine, "override .RECIPEPREFIX = !\n"),
+        '!'
+    );
+    assert_eq!(
+        read(&mut names, &mut engine, "override undefine .RECIPEPREFIX\n"),
+        '\t'
+    );
+}
+
+#[test]
+fn double_colon_recipes() {
+    crate::test::setup();
+    let block = create_span(
+        r#"
+all:: a
+	@echo a
+
+all:: b
+	@echo b
+"#,
+    );
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let mut i = block.span();
+    while i.len() > 0 {
+        i = assert_ok!(parse_state.parse_line(i, &mut names, &mut engine)).0;
+    }
+    parse_state.close_rule(&mut names, &mut engine);
+
+    // Each double-colon rule has its own recipe, so nothing is overridden
+    assert!(engine.diagnostics().is_empty());
+    let all = names.file_name("all").unwrap();
+    let rules = engine.database.double_colon_rules(all).to_vec();
+    assert_eq!(rules.len(), 2);
+    for (rule, expected) in rules.iter().zip(&["a", "b"]) {
+        let deps: Vec<String> = rule
+            .dependencies()
+            .iter()
+            .map(|dep| dep.into_string())
+            .collect();
+        assert_eq!(deps, vec![expected.to_string()]);
+        assert_eq!(rule.recipe().commands().len(), 1);
+    }
+    assert_eq!(rules[0].location(), Location::test_location(2, 1));
+    assert_eq!(rules[1].location(), Location::test_location(5, 1));
+
+    // Mixing `:` and `::` rules is an error, and the new rule is left out
+    let block = create_span("all: c\n");
+    assert_ok!(parse_state.parse_line(block.span(), &mut names, &mut engine));
+    parse_state.close_rule(&mut names, &mut engine);
+    match engine.diagnostics() {
+        [crate::Diagnostic::MixedRuleTypes { target, .. }] => assert_eq!(*target, all),
+        diagnostics => panic!("unexpected diagnostics {:?}", diagnostics),
+    }
+    assert_eq!(engine.database.double_colon_rules(all).len(), 2);
+    assert_eq!(engine.database.get_rule(all), Some(&rules[1]));
+}
+
+#[test]
+fn static_pattern_with_second_expansion() {
+    crate::test::setup();
+    let block = create_span(".SECONDEXPANSION:\na.o: %.o: $$x\n");
+
+    let mut engine: Engine = Default::default();
+    let mut parse_state = ParserState::new();
+    let mut names = crate::NameCache::default();
+
+    let i = assert_ok!(parse_state.parse_line(block.span(), &mut names, &mut engine)).0;
+    assert!(engine.second_expansion);
+    // Static pattern rules aren't supported, but they must not be read as a
+    // plain rule with deferred prerequisites either
+    assert_err_contains!(
+        parse_state
+            .parse_line(i, &mut names, &mut engine)
+            .unwrap_err(),
+        crate::ParseErrorKind::StaticPatternRule
+    );
+    assert!(names.file_name("a.o").is_none());
+}
@@ -4,11 +4,12 @@
 use super::{error_out, makefile_whitespace};
 use crate::ast;
 use crate::eval::Flavor;
+use crate::eval::Origin;
 use crate::eval::VariableParameters;
 use crate::evaluated::BlockSpan;
 use crate::parsers::ast::parse_ast;
 use crate::parsers::DefineState;
-use crate::{Engine, ParseErrorKind, VariableName};
+use crate::{Diagnostic, Engine, ParseErrorKind, VariableName};
 use nom::IResult;
 
 #[cfg(test)]
@@ -19,6 +20,9 @@ enum AssignmentType {
     Recursive,
     Conditional,
     Simple,
+    /// POSIX `:::=`, which expands the value immediately but creates a
+    /// recursive variable
+    PosixSimple,
     Append,
     Bang,
 }
@@ -37,8 +41,10 @@ enum DefineLineAction {
 /// Structure of a define line
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct DefineLine {
-    /// The content of the line
+    /// The content of the line, without its line ending
     content: ast::AstNode,
+    /// The line ending, if the line wasn't the last in the block
+    line_ending: Option<ast::AstNode>,
     /// What action to take with the content
     action: DefineLineAction,
     /// The location at which this line starts
@@ -52,6 +58,8 @@ pub(crate) enum Action {
     Define(VariableParameters),
     /// Append to the specified variable
     Append(ast::AstNode),
+    /// Remove the variable
+    Undefine,
 }
 
 /// Block of modifiers
@@ -68,44 +76,228 @@ pub(crate) struct Modifiers {
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct VariableAction {
     pub name: VariableName,
+    pub location: crate::source_location::Location,
     pub modifiers: Modifiers,
     pub action: Action,
+    /// Whether a `define` body is expanded when it is assigned at `endef`
+    /// (`:=`, `::=` and `:::=`). Other assignments expand their value while
+    /// being parsed.
+    pub expand_define: bool,
 }
 
 impl crate::parsers::ParserState {
     pub(crate) fn handle_global_variable_action(
         &mut self,
+        names: &mut crate::NameCache,
         engine: &mut Engine,
         action: VariableAction,
     ) -> Result<(), ParseErrorKind> {
-        if action.modifiers.export
-            || action.modifiers.mod_override
-            || action.modifiers.private
-            || action.modifiers.undefine
-        {
-            unimplemented!("Handling of variable with modifiers {:?}", action.modifiers);
-        }
-
-        // If this is a define, just put the parser into define mode
+        // If this is a define, just put the parser into define mode. The
+        // assignment itself happens once the body is complete
         if action.modifiers.define {
-            assert!(!self.current_define.is_some());
+            assert!(self.current_define.is_none());
             self.current_define = Some(DefineState {
-                var: action.name,
+                action,
                 nesting: 1,
+                body: Vec::new(),
+                line_ending: None,
+            });
+            return Ok(());
+        }
+
+        let origin = if action.modifiers.mod_override {
+            Origin::Override
+        } else {
+            Origin::File
+        };
+        // `?=` leaves variables that are already defined alone
+        let already_defined = match &action.action {
+            Action::Define(parameters) if parameters.flavor == Flavor::Conditional => {
+                engine.database.get_variable(action.name).is_some()
+            }
+            _ => false,
+        };
+        let blocking_origin = if already_defined {
+            None
+        } else {
+            engine.database.blocking_origin(action.name, origin)
+        };
+        if let Some(existing_origin) = blocking_origin {
+            debug!(
+                "Ignoring assignment to {:?}, it was set by {:?}",
+                action.name, existing_origin
+            );
+            engine.push_diagnostic(Diagnostic::IgnoredAssignment {
+                variable: action.name,
+                target: None,
+                location: action.location.clone(),
+                origin,
+                existing_origin,
             });
         }
 
-        match action.action {
-            Action::Define(parameters) => {
-                engine.database = engine.database.set_variable(action.name, parameters);
+        if blocking_origin.is_none() && !already_defined {
+            match action.action {
+                Action::Define(mut parameters) => {
+                    conditional_assignment(action.name, &mut parameters);
+                    parameters.origin = origin;
+                    parameters.private = action.modifiers.private;
+                    parameters.global_sensitivity = self.global_sensitivity();
+                    engine.database = engine.database.set_variable(action.name, parameters);
+                }
+                Action::Append(node) => {
+                    let flavor = engine
+                        .database
+                        .get_variable(action.name)
+                        .map(|variable| variable.flavor());
+                    let node =
+                        appended_value(names, engine, flavor, node, &self.global_sensitivity());
+                    let mut parameters = VariableParameters::new(node, Flavor::Recursive, origin);
+                    parameters.private = action.modifiers.private;
+                    parameters.global_sensitivity = self.global_sensitivity();
+                    engine.database = engine.database.append_to_variable(
+                        action.name,
+                        parameters.unexpanded_value.location(),
+                        parameters,
+                        true,
+                    )
+                }
+                Action::Undefine => {
+                    engine.database =
+                        engine
+                            .database
+                            .undefine_variable(action.name, action.location, origin)
+                }
+            }
+        }
+
+        if action.modifiers.export {
+            engine.database = engine.database.set_variable_export(action.name, true);
+        }
+        engine.global_variable_changed(names, action.name);
+
+        Ok(())
+    }
+
+    /// Handle a target-specific variable assignment, applying it to each of
+    /// the targets
+    pub(crate) fn handle_target_variable_action(
+        &mut self,
+        names: &mut crate::NameCache,
+        engine: &mut Engine,
+        targets: &[std::sync::Arc<crate::evaluated::Block>],
+        action: VariableAction,
+    ) -> Result<(), ParseErrorKind> {
+        if action.modifiers.define {
+            return Err(ParseErrorKind::TargetSpecificDefine);
+        }
+
+        // Target-specific variables shadow global ones, except for values from
+        // the command line or `-e`, which only `override` can beat
+        let origin = if action.modifiers.mod_override {
+            Origin::Override
+        } else {
+            Origin::File
+        };
+        let existing_origin = engine
+            .database
+            .get_variable(action.name)
+            .map(|variable| variable.origin());
+        let global_blocking_origin = match existing_origin {
+            Some(existing @ Origin::Command) | Some(existing @ Origin::EnvironmentOverride)
+                if origin != Origin::Override && action.action != Action::Undefine =>
+            {
+                Some(existing)
+            }
+            _ => None,
+        };
+
+        for target in targets {
+            let target = names.intern_file_name(target.into_string());
+            // Only `override undefine` can remove an `override` target variable
+            let blocking_origin = global_blocking_origin.or_else(|| {
+                let existing = engine
+                    .database
+                    .get_variable_for_target(target, action.name)?
+                    .origin();
+                if action.action == Action::Undefine && existing.priority() > origin.priority() {
+                    Some(existing)
+                } else {
+                    None
+                }
+            });
+            // `?=` only applies if the variable isn't defined for the target
+            // or globally
+            if let Action::Define(parameters) = &action.action {
+                if parameters.flavor == Flavor::Conditional
+                    && (engine
+                        .database
+                        .get_variable_for_target(target, action.name)
+                        .is_some()
+                        || engine.database.get_variable(action.name).is_some())
+                {
+                    continue;
+                }
+            }
+
+            if let Some(existing_origin) = blocking_origin {
+                engine.push_diagnostic(Diagnostic::IgnoredAssignment {
+                    variable: action.name,
+                    target: Some(target),
+                    location: action.location.clone(),
+                    origin,
+                    existing_origin,
+                });
+            } else {
+                engine.database = match &action.action {
+                    Action::Define(parameters) => {
+                        let mut parameters = parameters.clone();
+                        conditional_assignment(action.name, &mut parameters);
+                        parameters.origin = origin;
+                        parameters.private = action.modifiers.private;
+                        parameters.global_sensitivity = self.global_sensitivity();
+                        engine
+                            .database
+                            .set_variable_for_target(target, action.name, parameters)
+                    }
+                    Action::Append(node) => {
+                        let flavor = engine
+                            .database
+                            .get_variable_for_target(target, action.name)
+                            .or_else(|| engine.database.get_variable(action.name))
+                            .map(|variable| variable.flavor());
+                        let node = appended_value(
+                            names,
+                            engine,
+                            flavor,
+                            node.clone(),
+                            &self.global_sensitivity(),
+                        );
+                        let mut parameters =
+                            VariableParameters::new(node.clone(), Flavor::Recursive, origin);
+                        parameters.private = action.modifiers.private;
+                        parameters.global_sensitivity = self.global_sensitivity();
+                        engine.database.append_to_variable_for_target(
+                            target,
+                            action.name,
+                            node.location(),
+                            parameters,
+                        )
+                    }
+                    Action::Undefine => engine.database.undefine_variable_for_target(
+                        target,
+                        action.name,
+                        action.location.clone(),
+                        origin,
+                    ),
+                };
             }
-            Action::Append(node) => {
-                engine.database = engine.database.append_to_variable(
-                    action.name,
-                    node.location(),
-                    VariableParameters::new(node, Flavor::Recursive, crate::eval::Origin::File),
-                    true,
-                )
+
+            if action.modifiers.export {
+                engine.database =
+                    engine
+                        .database
+                        .set_target_variable_export(target, action.name, true);
             }
         }
 
@@ -115,15 +307,16 @@ impl crate::parsers::ParserState {
     /// Handle a line inside of a define
     pub(crate) fn handle_define_line(
         &mut self,
+        names: &mut crate::NameCache,
         engine: &mut Engine,
-        action: DefineLine,
+        line: DefineLine,
     ) -> Result<(), ParseErrorKind> {
         let define = self
             .current_define
             .as_mut()
             .expect("handle_define_line called when not defining anything");
 
-        match action.action {
+        match line.action {
             DefineLineAction::Append => {
                 // No special handling, we always append anyway.
             }
@@ -133,29 +326,65 @@ impl crate::parsers::ParserState {
             DefineLineAction::DecreaseNesting => {
                 define.nesting -= 1;
                 if define.nesting == 0 {
-                    // If we reach 0 nesting, unset the define line and return immediately
-                    self.current_define = None;
-                    return Ok(());
+                    // If we reach 0 nesting, the define is complete
+                    let define = self.current_define.take().unwrap();
+                    return self.finish_define(names, engine, define);
                 }
             }
         }
 
-        engine.database = engine.database.append_to_variable(
-            define.var,
-            action.location,
-            VariableParameters::new(action.content, Flavor::Recursive, crate::eval::Origin::File),
-            false,
-        );
+        if let Some(line_ending) = define.line_ending.take() {
+            define.body.push(line_ending);
+        }
+        define.body.push(line.content);
+        define.line_ending = line.line_ending;
 
         Ok(())
     }
+
+    /// Assign the body of a completed define, using the operator from the
+    /// `define` line
+    fn finish_define(
+        &mut self,
+        names: &mut crate::NameCache,
+        engine: &mut Engine,
+        define: DefineState,
+    ) -> Result<(), ParseErrorKind> {
+        let mut action = define.action;
+        let body = if define.body.is_empty() {
+            ast::empty()
+        } else {
+            ast::collapsing_concat(action.location.clone(), define.body)
+        };
+
+        action.modifiers.define = false;
+        action.action = match action.action {
+            Action::Define(mut parameters) => {
+                // `:::=` expands the body like `:=`, but the variable stays
+                // recursive
+                parameters.unexpanded_value = if action.expand_define {
+                    let contents = body.eval_within(names, engine, &self.global_sensitivity());
+                    ast::preevaluated(body.location(), contents)
+                } else {
+                    body
+                };
+                Action::Define(parameters)
+            }
+            Action::Append(_) => Action::Append(body),
+            Action::Undefine => unreachable!("undefine never starts a define"),
+        };
+
+        self.handle_global_variable_action(names, engine, action)
+    }
 }
 
-/// Attempt to parse a variable reference in the context of a given database
+/// Attempt to parse a variable reference in the context of a given database.
+/// `enclosing` is the global sensitivity of the line.
 pub(crate) fn parse_line<'a>(
     i: BlockSpan<'a>,
     names: &mut crate::NameCache,
     context: &mut crate::Engine,
+    enclosing: &crate::types::Set<VariableName>,
 ) -> IResult<BlockSpan<'a>, VariableAction, ParseErrorKind> {
     use nom::Slice;
 
@@ -173,13 +402,14 @@ pub(crate) fn parse_line<'a>(
     if i.len() == 0 {
         return error_out(i, ParseErrorKind::InternalFailure("no content on line"));
     }
+    let location = i.location().expect("line should have nonzero length");
 
     // Match modifiers
     let mut modifiers: Modifiers = Default::default();
     loop {
         // If parsing as a variable definition succeeds, we're done
         debug!("Parsing iteration {:?}", i.into_string());
-        match parse_variable_assignment(i, modifiers, names, context) {
+        match parse_variable_assignment(i, modifiers, location.clone(), names, context, enclosing) {
             Ok(v) => return Ok(v),
             Err(e) => {
                 match e.clone().into_error_kind() {
@@ -229,20 +459,25 @@ pub(crate) fn parse_line<'a>(
                         if trailing.len() == 0 {
                             // TODO: propagate sensitivity from the name?
                             let (_, name_ast) = parse_ast(variable_name)?;
-                            let variable_name = name_ast.eval(names, context);
+                            let variable_name = name_ast.eval_within(names, context, enclosing);
                             let variable_name = variable_name.into_string().trim().into();
                             let variable_name = names.intern_variable_name(variable_name);
+                            let expand_define = assignment_type == AssignmentType::Simple
+                                || assignment_type == AssignmentType::PosixSimple;
                             return Ok((
                                 trailing,
                                 VariableAction {
                                     name: variable_name,
+                                    location,
                                     modifiers: modifiers,
+                                    expand_define,
                                     action: match assignment_type {
                                         AssignmentType::Append => Action::Append(ast::empty()),
                                         v => Action::Define(VariableParameters::new(
                                             ast::empty(),
                                             match v {
                                                 AssignmentType::Simple => Flavor::Simple,
+                                                AssignmentType::PosixSimple => Flavor::Recursive,
                                                 AssignmentType::Conditional => Flavor::Conditional,
                                                 AssignmentType::Recursive => Flavor::Recursive,
                                                 AssignmentType::Bang => Flavor::Shell,
@@ -263,7 +498,9 @@ pub(crate) fn parse_line<'a>(
                     rest,
                     VariableAction {
                         name: variable_name,
+                        location,
                         modifiers: modifiers,
+                        expand_define: false,
                         action: Action::Define(VariableParameters::new(
                             ast::empty(),
                             Flavor::Recursive,
@@ -274,7 +511,22 @@ pub(crate) fn parse_line<'a>(
             }
             Modifier::Undefine => {
                 modifiers.undefine = true;
-                unimplemented!("undefine modifier");
+                // The rest of the line names the variable
+                let (_, name_ast) = parse_ast(i)?;
+                let variable_name = name_ast.eval_within(names, context, enclosing);
+                let variable_name = variable_name.into_string().trim().into();
+                let variable_name = names.intern_variable_name(variable_name);
+                let rest = i.slice(i.len()..);
+                return Ok((
+                    rest,
+                    VariableAction {
+                        name: variable_name,
+                        location,
+                        modifiers,
+                        expand_define: false,
+                        action: Action::Undefine,
+                    },
+                ));
             }
         }
     }
@@ -309,6 +561,7 @@ fn parse_assignment_operator<'a>(
         i,
         alt!(
             pe_complete!(pe_fix!(tag!("="))) => { |_| { AssignmentType::Recursive }} |
+            pe_complete!(pe_fix!(tag!(":::="))) => { |_| { AssignmentType::PosixSimple }} |
             pe_complete!(pe_fix!(tag!("::="))) => { |_| { AssignmentType::Simple }} |
             pe_complete!(pe_fix!(tag!(":="))) => { |_| { AssignmentType::Simple }} |
             pe_complete!(pe_fix!(tag!("?="))) => { |_| { AssignmentType::Conditional }} |
@@ -325,8 +578,10 @@ fn parse_assignment_operator<'a>(
 fn parse_variable_assignment<'a>(
     i: BlockSpan<'a>,
     modifiers: Modifiers,
+    location: crate::source_location::Location,
     names: &mut crate::NameCache,
     context: &mut crate::Engine,
+    enclosing: &crate::types::Set<VariableName>,
 ) -> IResult<BlockSpan<'a>, VariableAction, ParseErrorKind> {
     let (i, (name_segment, assignment_type)) = parse_assignment_operator(i)?;
     let (value_segment, _) = makefile_whitespace(i)?;
@@ -334,17 +589,16 @@ fn parse_variable_assignment<'a>(
     let (_, name_ast) = parse_ast(name_segment)?;
     let (post_value, mut value_ast) = parse_ast(value_segment)?;
 
-    let variable_name = name_ast.eval(names, context);
+    let variable_name = name_ast.eval_within(names, context, enclosing);
     let variable_name = variable_name.into_string().trim().into();
     let variable_name = names.intern_variable_name(variable_name);
 
-    if assignment_type == AssignmentType::Simple {
-        // Evaluate the value AST
-        let contents = value_ast.eval(names, context);
-        let location = value_segment
-            .location()
-            .expect("value segment should have nonzero length");
-        value_ast = ast::preevaluated(location.into(), contents);
+    if assignment_type == AssignmentType::Simple || assignment_type == AssignmentType::PosixSimple {
+        // Evaluate the value AST now, keeping the result (rather than the
+        // text) so later expansions don't evaluate it again
+        let contents = value_ast.eval_within(names, context, enclosing);
+        let location = value_segment.location().unwrap_or_else(|| location.clone());
+        value_ast = ast::preevaluated(location, contents);
     }
 
     // TODO: the origin reported here is not necessarily correct. We need to
@@ -354,13 +608,16 @@ fn parse_variable_assignment<'a>(
         post_value,
         VariableAction {
             name: variable_name,
+            location,
             modifiers: modifiers,
+            expand_define: false,
             action: match assignment_type {
                 AssignmentType::Append => Action::Append(value_ast),
                 v => Action::Define(VariableParameters::new(
                     value_ast,
                     match v {
                         AssignmentType::Simple => Flavor::Simple,
+                        AssignmentType::PosixSimple => Flavor::Recursive,
                         AssignmentType::Conditional => Flavor::Conditional,
                         AssignmentType::Recursive => Flavor::Recursive,
                         AssignmentType::Bang => Flavor::Shell,
@@ -373,6 +630,122 @@ fn parse_variable_assignment<'a>(
     ))
 }
 
+/// Turn a `?=` that is going ahead into an ordinary recursive assignment.
+/// Since the assignment only happened because `name` was undefined, the
+/// value is made sensitive to `name` itself.
+fn conditional_assignment(name: VariableName, parameters: &mut VariableParameters) {
+    use crate::evaluated::Block;
+
+    if parameters.flavor != Flavor::Conditional {
+        return;
+    }
+
+    let location = parameters.unexpanded_value.location();
+    let mut sensitivity: crate::types::Set<VariableName> = Default::default();
+    sensitivity.insert(name);
+    let definedness = Block::new(sensitivity, Vec::new());
+    parameters.flavor = Flavor::Recursive;
+    parameters.unexpanded_value = ast::collapsing_concat(
+        location.clone(),
+        vec![
+            ast::preevaluated(location, definedness),
+            std::mem::replace(&mut parameters.unexpanded_value, ast::empty()),
+        ],
+    );
+}
+
+/// Prepare the value appended by `+=` to a variable with the given flavor
+/// (`None` if it isn't defined yet). Appending to a simple variable expands
+/// the new text immediately, as `:=` would; otherwise it is kept unexpanded.
+/// `enclosing` is the global sensitivity of the line.
+fn appended_value(
+    names: &mut crate::NameCache,
+    engine: &mut Engine,
+    flavor: Option<Flavor>,
+    node: ast::AstNode,
+    enclosing: &crate::types::Set<VariableName>,
+) -> ast::AstNode {
+    match flavor {
+        Some(Flavor::Simple) => {
+            let contents = node.eval_within(names, engine, enclosing);
+            ast::preevaluated(node.location(), contents)
+        }
+        _ => node,
+    }
+}
+
+/// Apply a `NAME=value` style assignment given on the command line
+pub(crate) fn define_command_line_variable(
+    names: &mut crate::NameCache,
+    engine: &mut Engine,
+    definition: &str,
+) -> Result<(), ParseErrorKind> {
+    use crate::evaluated::{self, Block};
+    use crate::source_location::LocatedString;
+
+    let block = Block::new(
+        Default::default(),
+        vec![evaluated::constant(LocatedString::synthetic_new(
+            definition,
+        ))],
+    );
+    let action = match parse_line(block.span(), names, engine, &Default::default()) {
+        Ok((_, action)) => action,
+        Err(_) => return Err(ParseErrorKind::MissingSeparator),
+    };
+    if action.action == Action::Undefine {
+        // `undefine` isn't an assignment, so make would treat it as a goal
+        return Err(ParseErrorKind::MissingSeparator);
+    }
+
+    if let Action::Define(parameters) = &action.action {
+        if parameters.flavor == Flavor::Conditional
+            && engine.database.get_variable(action.name).is_some()
+        {
+            return Ok(());
+        }
+    }
+
+    if let Some(existing_origin) = engine
+        .database
+        .blocking_origin(action.name, Origin::Command)
+    {
+        engine.push_diagnostic(Diagnostic::IgnoredAssignment {
+            variable: action.name,
+            target: None,
+            location: action.location,
+            origin: Origin::Command,
+            existing_origin,
+        });
+        return Ok(());
+    }
+
+    engine.database = match action.action {
+        Action::Define(mut parameters) => {
+            conditional_assignment(action.name, &mut parameters);
+            parameters.origin = Origin::Command;
+            engine.database.set_variable(action.name, parameters)
+        }
+        Action::Append(node) => {
+            let flavor = engine
+                .database
+                .get_variable(action.name)
+                .map(|variable| variable.flavor());
+            let node = appended_value(names, engine, flavor, node, &Default::default());
+            engine.database.append_to_variable(
+                action.name,
+                node.location(),
+                VariableParameters::new(node, Flavor::Recursive, Origin::Command),
+                true,
+            )
+        }
+        Action::Undefine => unreachable!("undefine was rejected above"),
+    };
+    engine.global_variable_changed(names, action.name);
+
+    Ok(())
+}
+
 /// Parse a line inside a define
 pub(crate) fn parse_define_line<'a>(
     i: BlockSpan<'a>,
@@ -381,21 +754,39 @@ pub(crate) fn parse_define_line<'a>(
     // TODO: maybe just grab a whole define in here? Don't bother with trying to
     // round-trip it through the processing function above
     // This also uses a specialized version of makefile_grab_line, since we
-    // actually need the line endings to stay intact.
-    let (next_lines, i) = fix_error!(
+    // actually need the line endings to stay intact. Comments are kept too,
+    // since they're part of the value.
+    use nom::Slice;
+    let (next_lines, line) = fix_error!(
         i,
         ParseErrorKind,
         alt_complete!(
-            take_until!("#") | // Comments
-                recognize!(terminated!(take_until!("\n"), tag!("\n"))) | // UNIX line endings
-                recognize!(terminated!(take_until!("\r\n"), tag!("\r\n"))) | // Windows line endings
+            recognize!(terminated!(take_until!("\n"), tag!("\n"))) | // Line endings
                 nom::rest // EOF
         )
     )?;
 
-    debug!("Parsing define line {:?}", i.into_string());
-    let location = i.location().expect("There should be content on the line");
+    debug!("Parsing define line {:?}", line.into_string());
+    let location = line
+        .location()
+        .expect("There should be content on the line");
+    let text = line.into_string();
+    let content_length = if text.ends_with("\r\n") {
+        text.len() - 2
+    } else if text.ends_with('\n') {
+        text.len() - 1
+    } else {
+        text.len()
+    };
+    let i = line.slice(..content_length);
+    let ending = line.slice(content_length..);
+
     let (_, ast) = parse_ast(i)?;
+    let line_ending = if ending.len() == 0 {
+        None
+    } else {
+        Some(parse_ast(ending)?.1)
+    };
 
     let (i, action) = pe_fix!(
         i,
@@ -410,7 +801,8 @@ pub(crate) fn parse_define_line<'a>(
     )?;
     let (i, _) = pe_fix!(i, take_while!(|c: char| c.is_whitespace()))?;
 
-    if action == DefineLineAction::DecreaseNesting && i.len() != 0 {
+    if action == DefineLineAction::DecreaseNesting && i.len() != 0 && i.chars().next() != Some('#')
+    {
         return super::fail_out(i, ParseErrorKind::ExtraTokensAfter("endef"));
     }
 
@@ -419,6 +811,7 @@ pub(crate) fn parse_define_line<'a>(
         DefineLine {
             action: action,
             content: ast,
+            line_ending,
             location: location,
         },
     ))
@@ -6,8 +6,12 @@ macro_rules! simple_test {
         let mut engine = Default::default();
         let mut names = Default::default();
         let block = create_span($line);
-        let (remaining, variable_action) =
-            assert_ok!(parse_line(block.span(), &mut names, &mut engine));
+        let (remaining, variable_action) = assert_ok!(parse_line(
+            block.span(),
+            &mut names,
+            &mut engine,
+            &Default::default()
+        ));
         assert_complete!(remaining);
 
         match variable_action.action {
@@ -12,7 +12,7 @@ macro_rules! define_test (
         let mut engine = Default::default();
         let mut names = Default::default();
         let block = create_span($span);
-        let (remaining, variable_action) = assert_ok!(parse_line(block.span(), &mut names, &mut engine));
+        let (remaining, variable_action) = assert_ok!(parse_line(block.span(), &mut names, &mut engine, &Default::default()));
         assert_complete!(remaining);
 
         match variable_action.action {
@@ -55,6 +55,18 @@ fn posix_simple_no_space() {
     define_test!("a::= b", Flavor::Simple, "a");
 }
 
+// #TST-P-Variable.posix_immediate
+#[test]
+fn posix_immediate() {
+    define_test!("a :::= b", Flavor::Recursive, "a");
+}
+
+// #TST-P-Variable.simple_empty
+#[test]
+fn simple_empty() {
+    define_test!("a :=", Flavor::Simple, "a");
+}
+
 // #TST-P-Variable.recursive
 
 // #TST-P-Variable.recursive_assignment
@@ -74,8 +86,12 @@ fn simple_modifiers() {
     let mut engine = Default::default();
     let mut names = Default::default();
     let block = create_span("export a = b");
-    let (remaining, variable_action) =
-        assert_ok!(parse_line(block.span(), &mut names, &mut engine));
+    let (remaining, variable_action) = assert_ok!(parse_line(
+        block.span(),
+        &mut names,
+        &mut engine,
+        &Default::default()
+    ));
     assert_complete!(remaining);
 
     match variable_action.action {
@@ -97,7 +113,12 @@ fn modifers_with_no_var() {
     let mut engine = Default::default();
     let mut names = Default::default();
     let block = create_span("export private");
-    let err = assert_err!(parse_line(block.span(), &mut names, &mut engine));
+    let err = assert_err!(parse_line(
+        block.span(),
+        &mut names,
+        &mut engine,
+        &Default::default()
+    ));
 
     assert_err_contains!(err, ParseErrorKind::InternalFailure("not an assignment"));
 }
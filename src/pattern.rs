@@ -1,17 +1,297 @@
 //! The "pattern engine" handles matching file names against lists of patterns
 
-/// A matcher for file names, compatible with Makefiles
-#[derive(Clone, Default, Debug)]
+use fxhash::FxHashMap;
+use std::collections::BTreeSet;
+
+/// A matcher for file names, compatible with Makefiles.
+/// Patterns may contain a `%`, which matches any (possibly empty) string;
+/// patterns without one only match names exactly. Like GNU make, only the
+/// first `%` is special.
+///
+/// Patterns are indexed by their literal prefix and suffix, so looking up a
+/// name costs a few hash lookups per distinct prefix and suffix length
+/// rather than a scan over every pattern.
+#[derive(Clone, Debug)]
 pub struct PatternEngine<T> {
-    patterns: Vec<String>,
-    mappings: Vec<T>,
+    /// Every pattern and its value, in insertion order
+    entries: Vec<(String, T)>,
+    /// Patterns without a `%`, by the name they match
+    exact: FxHashMap<String, Vec<usize>>,
+    /// Patterns with a `%`, by their literal prefix and then suffix
+    wildcards: FxHashMap<String, FxHashMap<String, Vec<usize>>>,
+    /// The distinct lengths of the prefixes in `wildcards`
+    prefix_lengths: BTreeSet<usize>,
+    /// The distinct lengths of the suffixes in `wildcards`
+    suffix_lengths: BTreeSet<usize>,
+}
+
+/// A pattern that matched a name
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PatternMatch<'a, T> {
+    /// The pattern that matched
+    pub pattern: &'a str,
+    /// The part of the name matched by the `%`. Empty for patterns without a
+    /// `%`.
+    pub stem: &'a str,
+    /// The value associated with the pattern
+    pub value: &'a T,
+}
+
+impl<T> Default for PatternEngine<T> {
+    fn default() -> Self {
+        PatternEngine {
+            entries: Vec::new(),
+            exact: Default::default(),
+            wildcards: Default::default(),
+            prefix_lengths: BTreeSet::new(),
+            suffix_lengths: BTreeSet::new(),
+        }
+    }
 }
 
 impl<T> PatternEngine<T> {
-    /// Get the list of values associated with the patterns matching the
-    /// provided query
-    pub fn matches(&self, query: &str) -> Vec<T> {
+    /// Create an empty pattern engine
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Associate a value with a pattern. The same pattern may be added
+    /// several times; each value is kept.
+    pub fn insert(&mut self, pattern: String, value: T) {
+        self.index(self.entries.len(), &pattern);
+        self.entries.push((pattern, value));
+    }
+
+    /// Remove every value associated with the pattern
+    pub fn remove(&mut self, pattern: &str) {
+        let before = self.entries.len();
+        self.entries.retain(|(existing, _)| existing != pattern);
+        if self.entries.len() != before {
+            self.reindex();
+        }
+    }
+
+    /// Remove all the patterns
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.reindex();
+    }
+
+    /// Check if there are no patterns
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the number of patterns (counting repeated patterns once per
+    /// insertion)
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Iterate over the patterns and their values, in the order they were
+    /// inserted
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.entries
+            .iter()
+            .map(|(pattern, value)| (pattern.as_str(), value))
+    }
+
+    /// Find every pattern matching the query, in the order they were
+    /// inserted, along with the stem each one matched
+    pub fn find<'a>(&'a self, query: &'a str) -> Vec<PatternMatch<'a, T>> {
         debug!("Attempting pattern search on {:?}", query);
-        unimplemented!("Pattern searching")
+        let mut found: Vec<usize> = self.exact.get(query).cloned().unwrap_or_default();
+
+        for &prefix_len in self.prefix_lengths.range(..=query.len()) {
+            if !query.is_char_boundary(prefix_len) {
+                continue;
+            }
+            let suffixes = match self.wildcards.get(&query[..prefix_len]) {
+                Some(suffixes) => suffixes,
+                None => continue,
+            };
+            for &suffix_len in self.suffix_lengths.range(..=query.len() - prefix_len) {
+                let suffix_start = query.len() - suffix_len;
+                if !query.is_char_boundary(suffix_start) {
+                    continue;
+                }
+                if let Some(indices) = suffixes.get(&query[suffix_start..]) {
+                    found.extend(indices);
+                }
+            }
+        }
+
+        found.sort_unstable();
+        found
+            .into_iter()
+            .map(|idx| {
+                let (pattern, value) = &self.entries[idx];
+                let stem = match pattern.find('%') {
+                    Some(percent) => &query[percent..query.len() + percent + 1 - pattern.len()],
+                    None => "",
+                };
+                PatternMatch {
+                    pattern,
+                    stem,
+                    value,
+                }
+            })
+            .collect()
+    }
+
+    /// Get the list of values associated with the patterns matching the
+    /// provided query, in the order they were inserted
+    pub fn matches(&self, query: &str) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.find(query)
+            .into_iter()
+            .map(|found| found.value.clone())
+            .collect()
+    }
+
+    /// Add the pattern of entry `idx` to the index
+    fn index(&mut self, idx: usize, pattern: &str) {
+        match pattern.find('%') {
+            Some(percent) => {
+                let (prefix, suffix) = (&pattern[..percent], &pattern[percent + 1..]);
+                self.prefix_lengths.insert(prefix.len());
+                self.suffix_lengths.insert(suffix.len());
+                self.wildcards
+                    .entry(prefix.to_string())
+                    .or_default()
+                    .entry(suffix.to_string())
+                    .or_default()
+                    .push(idx);
+            }
+            None => self.exact.entry(pattern.to_string()).or_default().push(idx),
+        }
+    }
+
+    /// Rebuild the index after entries have been removed
+    fn reindex(&mut self) {
+        self.exact.clear();
+        self.wildcards.clear();
+        self.prefix_lengths.clear();
+        self.suffix_lengths.clear();
+        let patterns: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(pattern, _)| pattern.clone())
+            .collect();
+        for (idx, pattern) in patterns.iter().enumerate() {
+            self.index(idx, pattern);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Check whether a pattern matches a name
+    fn pattern_matches(pattern: &str, name: &str) -> bool {
+        match pattern.find('%') {
+            Some(idx) => {
+                let (prefix, suffix) = (&pattern[..idx], &pattern[idx + 1..]);
+                name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix)
+                    && name.ends_with(suffix)
+            }
+            None => pattern == name,
+        }
+    }
+
+    fn found<'a>(engine: &'a PatternEngine<u32>, query: &'a str) -> Vec<(&'a str, &'a str, u32)> {
+        engine
+            .find(query)
+            .into_iter()
+            .map(|found| (found.pattern, found.stem, *found.value))
+            .collect()
+    }
+
+    #[test]
+    fn insertion_order_and_stems() {
+        let mut engine = PatternEngine::new();
+        engine.insert("%.c".into(), 1);
+        engine.insert("src/%".into(), 2);
+        engine.insert("src/main.c".into(), 3);
+        engine.insert("src/%.c".into(), 4);
+        engine.insert("%.c".into(), 5);
+        engine.insert("%".into(), 6);
+        engine.insert("%.h".into(), 7);
+
+        assert_eq!(
+            found(&engine, "src/main.c"),
+            vec![
+                ("%.c", "src/main", 1),
+                ("src/%", "main.c", 2),
+                ("src/main.c", "", 3),
+                ("src/%.c", "main", 4),
+                ("%.c", "src/main", 5),
+                ("%", "src/main.c", 6),
+            ]
+        );
+        assert_eq!(engine.matches("a.h"), vec![6, 7]);
+        // The stem may be empty, but the prefix and suffix can't overlap
+        assert_eq!(found(&engine, "src/.c")[2], ("src/%.c", "", 4));
+        assert_eq!(engine.matches("src"), vec![6]);
+    }
+
+    #[test]
+    fn only_the_first_percent_is_special() {
+        let mut engine = PatternEngine::new();
+        engine.insert("a%b%c".into(), 1);
+        assert_eq!(found(&engine, "axb%c"), vec![("a%b%c", "x", 1)]);
+        assert!(engine.matches("axbyc").is_empty());
+    }
+
+    #[test]
+    fn multibyte_names() {
+        let mut engine = PatternEngine::new();
+        engine.insert("é%ü".into(), 1);
+        engine.insert("%.c".into(), 2);
+        assert_eq!(found(&engine, "éxü"), vec![("é%ü", "x", 1)]);
+        assert_eq!(found(&engine, "ü.c"), vec![("%.c", "ü", 2)]);
+        assert!(engine.matches("ü").is_empty());
+    }
+
+    #[test]
+    fn removal() {
+        let mut engine = PatternEngine::new();
+        engine.insert("%.c".into(), 1);
+        engine.insert("%.h".into(), 2);
+        engine.insert("%.c".into(), 3);
+        engine.insert("main.c".into(), 4);
+
+        engine.remove("%.c");
+        assert_eq!(engine.len(), 2);
+        assert_eq!(engine.matches("main.c"), vec![4]);
+        assert_eq!(engine.matches("main.h"), vec![2]);
+        assert_eq!(
+            engine.iter().collect::<Vec<_>>(),
+            vec![("%.h", &2), ("main.c", &4)]
+        );
+
+        engine.clear();
+        assert!(engine.is_empty());
+        assert!(engine.matches("main.c").is_empty());
+    }
+
+    #[test]
+    fn agrees_with_pattern_matches() {
+        let patterns = ["%", "%.c", "a%", "a%c", "abc", "ab%bc", "%%", ""];
+        let names = ["", "a", "c", "ac", "abc", "abbc", "abcbc", "x.c", "%"];
+        let mut engine = PatternEngine::new();
+        for (idx, pattern) in patterns.iter().enumerate() {
+            engine.insert(pattern.to_string(), idx);
+        }
+        for name in &names {
+            let expected: Vec<usize> = (0..patterns.len())
+                .filter(|idx| pattern_matches(patterns[*idx], name))
+                .collect();
+            assert_eq!(engine.matches(name), expected, "matching {:?}", name);
+        }
     }
 }
@@ -0,0 +1,156 @@
+//! Analysis of GNU make's "remaking makefiles" step. After reading all the
+//! makefiles, make tries to build any included makefile that didn't exist
+//! (usually generated dependency files), and if it built one it throws away
+//! everything it read and starts again. We never run recipes, so instead we
+//! record what make would try to build, and let the caller supply the
+//! generated content before re-reading. See
+//! [`Engine::remake_plan`](../struct.Engine.html#method.remake_plan).
+
+use crate::pattern::PatternEngine;
+use crate::source_location::Location;
+use crate::{Database, Engine, FileName, NameCache, Rule};
+
+/// An included makefile that couldn't be found when it was included
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingInclude {
+    /// The name given to the `include` directive
+    pub name: String,
+    /// True if the makefile was included with `-include` (or `sinclude`), so
+    /// make doesn't complain if it can't be built
+    pub soft: bool,
+    /// The location of the `include` directive
+    pub location: Location,
+}
+
+/// What make would do about a missing included makefile
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RemakeAction {
+    /// Build the makefile with the rule for the given target (which may be
+    /// a pattern), then restart
+    Build(FileName),
+    /// There's no rule to build the makefile, but it was included softly so
+    /// it's silently left out
+    Ignore,
+    /// There's no rule to build the makefile, so make stops with an error
+    Fail,
+}
+
+/// Everything make would do to remake missing makefiles before restarting
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RemakePlan {
+    /// Each missing makefile (in the order they were included) and what
+    /// would be done about it
+    pub makefiles: Vec<(MissingInclude, RemakeAction)>,
+}
+
+impl RemakePlan {
+    pub(crate) fn new(names: &NameCache, engine: &Engine, missing: &[MissingInclude]) -> Self {
+        let patterns = pattern_rules(names, &engine.database);
+        let makefiles = missing
+            .iter()
+            .map(|include| {
+                let action = match rule_for_makefile(
+                    names,
+                    engine,
+                    &patterns,
+                    &include.name,
+                    &mut Vec::new(),
+                ) {
+                    Some(target) => RemakeAction::Build(target),
+                    None if include.soft => RemakeAction::Ignore,
+                    None => RemakeAction::Fail,
+                };
+                (include.clone(), action)
+            })
+            .collect();
+        RemakePlan { makefiles }
+    }
+
+    /// Check whether make would build some makefiles and then restart
+    pub fn needs_restart(&self) -> bool {
+        self.makefiles
+            .iter()
+            .any(|(_, action)| matches!(action, RemakeAction::Build(_)))
+    }
+
+    /// Iterate over the names of the makefiles that would be built before
+    /// the restart. Their contents should be passed to
+    /// [`Engine::restart`](../struct.Engine.html#method.restart).
+    pub fn to_build(&self) -> impl Iterator<Item = &str> {
+        self.makefiles
+            .iter()
+            .filter(|(_, action)| matches!(action, RemakeAction::Build(_)))
+            .map(|(include, _)| include.name.as_str())
+    }
+
+    /// Iterate over the makefiles that can't be built and weren't included
+    /// softly. Make stops with "No rule to make target" for the first one.
+    pub fn failures(&self) -> impl Iterator<Item = &MissingInclude> {
+        self.makefiles
+            .iter()
+            .filter(|(_, action)| *action == RemakeAction::Fail)
+            .map(|(include, _)| include)
+    }
+}
+
+/// Check whether a rule has a recipe, so it can actually build its target
+fn has_recipe(rule: &Rule) -> bool {
+    !rule.recipe().commands().is_empty()
+}
+
+/// Index the pattern rules that have recipes, in the order they were defined
+fn pattern_rules<'a>(names: &NameCache, database: &'a Database) -> PatternEngine<&'a Rule> {
+    let mut patterns = PatternEngine::new();
+    for rule in database.rules().filter(|rule| has_recipe(rule)) {
+        if let Some(pattern) = names.resolve_file_name(rule.target_fname()) {
+            if pattern.contains('%') {
+                patterns.insert(pattern.to_string(), rule);
+            }
+        }
+    }
+    patterns
+}
+
+/// Find a rule with a recipe that can build the makefile. Explicit rules win
+/// over pattern rules, which are tried in the order they were defined. Like
+/// GNU make, a pattern rule only applies if each of its prerequisites (with
+/// the stem substituted) exists, has a rule of its own, or can be made by
+/// another pattern rule. `chain` holds the pattern rules already in use, as
+/// a rule can't appear twice in a chain.
+fn rule_for_makefile(
+    names: &NameCache,
+    engine: &Engine,
+    patterns: &PatternEngine<&Rule>,
+    name: &str,
+    chain: &mut Vec<FileName>,
+) -> Option<FileName> {
+    if let Some(rule) = names
+        .file_name(name)
+        .and_then(|target| engine.database.get_rule(target))
+    {
+        if has_recipe(rule) {
+            return Some(rule.target_fname());
+        }
+    }
+
+    for found in patterns.find(name) {
+        let target = found.value.target_fname();
+        if chain.contains(&target) {
+            continue;
+        }
+        chain.push(target);
+        let applies = found.value.dependencies().iter().all(|dependency| {
+            let dependency = dependency.into_string().replacen('%', found.stem, 1);
+            engine.file_exists(&dependency)
+                || names
+                    .file_name(&dependency)
+                    .is_some_and(|file| engine.database.get_rule(file).is_some())
+                || rule_for_makefile(names, engine, patterns, &dependency, chain).is_some()
+        });
+        chain.pop();
+        if applies {
+            return Some(target);
+        }
+    }
+    None
+}
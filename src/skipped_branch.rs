@@ -0,0 +1,66 @@
+//! Branches of conditionals (`ifeq`, `ifdef`, ...) that were not interpreted.
+//! These are kept around so that tools can show which parts of a makefile
+//! are dead under the current configuration, and what they contain.
+
+use crate::evaluated::Block;
+use crate::source_location::Location;
+use std::sync::Arc;
+
+/// Why the lines of a conditional branch were skipped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The condition guarding the branch didn't hold (for an `else`, there
+    /// was no earlier branch to skip it for)
+    ConditionFalse,
+    /// An earlier branch of the same `else` chain was interpreted, so this
+    /// one was skipped without evaluating its condition
+    EarlierBranchTaken,
+}
+
+/// A conditional branch whose lines were thrown away unevaluated
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkippedBranch {
+    directive: Arc<Block>,
+    reason: SkipReason,
+    lines: Vec<Arc<Block>>,
+}
+
+impl SkippedBranch {
+    pub(crate) fn new(directive: Arc<Block>, reason: SkipReason) -> Self {
+        Self {
+            directive,
+            reason,
+            lines: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push_line(&mut self, line: Arc<Block>) {
+        self.lines.push(line);
+    }
+
+    /// Get the directive guarding the branch, such as `ifdef FOO`,
+    /// `else ifeq (a,b)` or a bare `else`
+    pub fn directive(&self) -> &Arc<Block> {
+        &self.directive
+    }
+
+    /// Get the location of the directive guarding the branch
+    pub fn location(&self) -> Location {
+        self.directive
+            .span()
+            .location()
+            .unwrap_or(Location::Synthetic)
+    }
+
+    /// Get why the branch was skipped
+    pub fn reason(&self) -> SkipReason {
+        self.reason
+    }
+
+    /// Get the unevaluated lines of the branch as they were written
+    /// (including any recipe prefix), with continuations already joined.
+    /// Conditionals nested inside the branch are included verbatim.
+    pub fn lines(&self) -> &[Arc<Block>] {
+        &self.lines
+    }
+}
@@ -0,0 +1,125 @@
+//! Handling of GNU make's special built-in target names (`.PHONY`,
+//! `.PRECIOUS`, etc.).
+//! See [the GNU make manual](https://www.gnu.org/software/make/manual/make.html#Special-Targets)
+//! for what each of these does.
+
+/// The special targets that change how other targets (or the whole makefile)
+/// are treated, rather than describing a file that may be built
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SpecialTarget {
+    Phony,
+    Precious,
+    Intermediate,
+    Secondary,
+    SecondExpansion,
+    DeleteOnError,
+    NotParallel,
+    Ignore,
+    Silent,
+    ExportAllVariables,
+    OneShell,
+    Posix,
+    LowResolutionTime,
+}
+
+impl SpecialTarget {
+    /// Find the special target with the given name, if there is one
+    pub(crate) fn from_name(name: &str) -> Option<SpecialTarget> {
+        Some(match name {
+            ".PHONY" => SpecialTarget::Phony,
+            ".PRECIOUS" => SpecialTarget::Precious,
+            ".INTERMEDIATE" => SpecialTarget::Intermediate,
+            ".SECONDARY" => SpecialTarget::Secondary,
+            ".SECONDEXPANSION" => SpecialTarget::SecondExpansion,
+            ".DELETE_ON_ERROR" => SpecialTarget::DeleteOnError,
+            ".NOTPARALLEL" => SpecialTarget::NotParallel,
+            ".IGNORE" => SpecialTarget::Ignore,
+            ".SILENT" => SpecialTarget::Silent,
+            ".EXPORT_ALL_VARIABLES" => SpecialTarget::ExportAllVariables,
+            ".ONESHELL" => SpecialTarget::OneShell,
+            ".POSIX" => SpecialTarget::Posix,
+            ".LOW_RESOLUTION_TIME" => SpecialTarget::LowResolutionTime,
+            _ => return None,
+        })
+    }
+
+    /// Apply the effects of this special target, given its prerequisites
+    pub(crate) fn apply<I>(
+        self,
+        attributes: &mut crate::types::Map<crate::FileName, TargetAttributes>,
+        flags: &mut GlobalFlags,
+        prerequisites: I,
+    ) where
+        I: IntoIterator<Item = crate::FileName>,
+    {
+        let mut prerequisites = prerequisites.into_iter().peekable();
+        let has_prerequisites = prerequisites.peek().is_some();
+        for prerequisite in prerequisites {
+            let target_attributes = attributes.entry(prerequisite).or_default();
+            match self {
+                SpecialTarget::Phony => target_attributes.phony = true,
+                SpecialTarget::Precious => target_attributes.precious = true,
+                SpecialTarget::Intermediate => target_attributes.intermediate = true,
+                SpecialTarget::Secondary => target_attributes.secondary = true,
+                SpecialTarget::Ignore => target_attributes.ignore_errors = true,
+                SpecialTarget::Silent => target_attributes.silent = true,
+                SpecialTarget::LowResolutionTime => target_attributes.low_resolution_time = true,
+                // The rest of the special targets ignore their prerequisites
+                _ => {}
+            }
+        }
+
+        match self {
+            SpecialTarget::Secondary if !has_prerequisites => flags.all_secondary = true,
+            SpecialTarget::Ignore if !has_prerequisites => flags.ignore_errors = true,
+            SpecialTarget::Silent if !has_prerequisites => flags.silent = true,
+            SpecialTarget::DeleteOnError => flags.delete_on_error = true,
+            SpecialTarget::NotParallel => flags.not_parallel = true,
+            SpecialTarget::ExportAllVariables => flags.export_all_variables = true,
+            SpecialTarget::OneShell => flags.one_shell = true,
+            SpecialTarget::Posix => flags.posix = true,
+            _ => {}
+        }
+    }
+}
+
+/// Attributes attached to a target by listing it as a prerequisite of a
+/// special target
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetAttributes {
+    /// The target was listed in `.PHONY`
+    pub phony: bool,
+    /// The target was listed in `.PRECIOUS`
+    pub precious: bool,
+    /// The target was listed in `.INTERMEDIATE`
+    pub intermediate: bool,
+    /// The target was listed in `.SECONDARY`
+    pub secondary: bool,
+    /// The target was listed in `.IGNORE`
+    pub ignore_errors: bool,
+    /// The target was listed in `.SILENT`
+    pub silent: bool,
+    /// The target was listed in `.LOW_RESOLUTION_TIME`
+    pub low_resolution_time: bool,
+}
+
+/// Flags set by special targets that apply to the whole makefile
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GlobalFlags {
+    /// `.DELETE_ON_ERROR` was declared
+    pub delete_on_error: bool,
+    /// `.NOTPARALLEL` was declared
+    pub not_parallel: bool,
+    /// `.SECONDARY` was declared without prerequisites, making every target secondary
+    pub all_secondary: bool,
+    /// `.IGNORE` was declared without prerequisites
+    pub ignore_errors: bool,
+    /// `.SILENT` was declared without prerequisites
+    pub silent: bool,
+    /// `.EXPORT_ALL_VARIABLES` was declared
+    pub export_all_variables: bool,
+    /// `.ONESHELL` was declared
+    pub one_shell: bool,
+    /// `.POSIX` was declared
+    pub posix: bool,
+}
@@ -25,3 +25,446 @@ fn simple_includes() {
 
     variable_set_to!(names, engine, "foo", "baz");
 }
+
+#[test]
+fn include_search_path() {
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+
+    engine.working_directory.push("tests");
+    engine.working_directory.push("include");
+    engine.include_directories = vec!["missing".into(), "first".into(), "second".into()];
+    engine.search_default_include_directories = false;
+
+    let inf = File::open("tests/include/search0.mk")
+        .expect("Failed to open test file tests/include/search0.mk");
+    let mut bufreader = std::io::BufReader::new(inf);
+
+    engine
+        .read_makefile(&mut names, &mut bufreader, "search0.mk")
+        .unwrap();
+
+    // The first directory containing the file wins
+    variable_set_to!(names, engine, "found", "first");
+
+    let includes = engine.includes();
+    assert_eq!(includes.len(), 1);
+    assert_eq!(includes[0].name, "searched.mk");
+    assert_eq!(
+        includes[0].directory,
+        Some(engine.working_directory.join("first"))
+    );
+    assert_eq!(
+        includes[0].path,
+        engine.working_directory.join("first").join("searched.mk")
+    );
+
+    // Directories that don't exist are dropped from the search path
+    let first = engine.working_directory.join("first");
+    let second = engine.working_directory.join("second");
+    assert_eq!(
+        engine.include_search_path(),
+        vec![first.clone(), second.clone()]
+    );
+    variable_set_to!(
+        names,
+        engine,
+        ".INCLUDE_DIRS",
+        format!("{} {}", first.to_string_lossy(), second.to_string_lossy())
+    );
+}
+
+#[test]
+fn same_name_in_different_directories() {
+    use hastur::source_location::Location;
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+
+    engine.working_directory.push("tests");
+    engine.working_directory.push("include");
+
+    let inf = File::open("tests/include/same_name.mk")
+        .expect("Failed to open test file tests/include/same_name.mk");
+    let mut bufreader = std::io::BufReader::new(inf);
+
+    engine
+        .read_makefile(&mut names, &mut bufreader, "./same_name.mk")
+        .unwrap();
+
+    // Each makefile has its own file name, from which its full path can be
+    // recovered
+    let mut paths = Vec::new();
+    for include in engine.includes() {
+        match &include.location {
+            Location::SourceLocation { filename, .. } => {
+                assert_eq!(
+                    names.makefile_path(*filename),
+                    Some(engine.working_directory.join("same_name.mk").as_path())
+                );
+            }
+            location => panic!("Unexpected include location {:?}", location),
+        }
+        paths.push(include.path.clone());
+    }
+    assert_eq!(
+        paths,
+        vec![
+            engine.working_directory.join("first/searched.mk"),
+            engine.working_directory.join("second/searched.mk"),
+        ]
+    );
+
+    let found = names.variable_name("found").unwrap();
+    let location = engine
+        .database
+        .get_variable(found)
+        .unwrap()
+        .ast()
+        .location();
+    match location {
+        Location::SourceLocation { filename, line, .. } => {
+            assert_eq!(line, 1);
+            assert_eq!(names.makefile_path(filename), Some(paths[1].as_path()));
+        }
+        location => panic!("Unexpected variable location {:?}", location),
+    }
+}
+
+#[test]
+fn in_memory_filesystem() {
+    use hastur::MemoryFileSystem;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.add_file("/project/common.mk", "shared := yes\n");
+    filesystem.add_file("/project/src/main.c", "");
+    filesystem.add_file("/project/src/util.c", "");
+    filesystem.add_file("/project/include/config.mk", "config := memory\n");
+    filesystem.add_file("/project/conf/b.mk", "globbed += b\n");
+    filesystem.add_file("/project/conf/a.mk", "globbed += a\n");
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+    assert!(filesystem.set_modified("/project/src/util.c", modified));
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.include_directories = vec!["include".into()];
+    engine.search_default_include_directories = false;
+    engine.filesystem = Arc::new(filesystem);
+
+    // The top-level makefile is an unsaved buffer; only its includes come
+    // from the file system
+    let makefile = "include common.mk config.mk\nlocal := $(shared)\n\
+                    include conf/*.mk\n-include none/*.mk\n";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    variable_set_to!(names, engine, "shared", "yes");
+    variable_set_to!(names, engine, "config", "memory");
+    variable_set_to!(names, engine, "local", "yes");
+    // Globs are expanded through the file system, in sorted order, and a
+    // glob that matches nothing is kept as it is
+    variable_set_to!(names, engine, "globbed", "a b");
+    assert_eq!(engine.includes()[2].name, "conf/a.mk");
+    assert_eq!(engine.missing_includes()[0].name, "none/*.mk");
+    assert_eq!(
+        engine.includes()[1].directory,
+        Some(std::path::PathBuf::from("/project/include"))
+    );
+
+    assert!(engine.file_exists("src/main.c"));
+    assert!(engine.file_exists("src"));
+    assert!(!engine.file_exists("src/missing.c"));
+    assert_eq!(engine.modification_time("src/util.c").unwrap(), modified);
+    assert!(engine.modification_time("src/missing.c").is_err());
+    assert_eq!(engine.wildcard("src/*.c"), vec!["src/main.c", "src/util.c"]);
+}
+
+#[test]
+fn special_variables() {
+    use hastur::MemoryFileSystem;
+    use std::sync::Arc;
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.add_file(
+        "/project/sub/part.mk",
+        "part_dir := $(dir $(lastword $(MAKEFILE_LIST)))\n",
+    );
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+    engine.filesystem = Arc::new(filesystem);
+    engine.command_line_goals = vec!["all".into(), "install".into()];
+    engine.make_level = 1;
+
+    let makefile = "
+top_dir := $(dir $(lastword $(MAKEFILE_LIST)))
+include sub/part.mk
+after := $(MAKEFILE_LIST)
+MAKE_VERSION := mine
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    variable_set_to!(names, engine, "top_dir", "./");
+    variable_set_to!(names, engine, "part_dir", "sub/");
+    variable_set_to!(names, engine, "after", "Makefile sub/part.mk");
+    variable_set_to!(names, engine, "MAKEFILE_LIST", "Makefile sub/part.mk");
+    variable_set_to!(names, engine, "CURDIR", "/project");
+    variable_set_to!(names, engine, "MAKECMDGOALS", "all install");
+    variable_set_to!(names, engine, "MAKELEVEL", "1");
+    variable_set_to!(names, engine, "MAKE", "make");
+    // Values set by the makefile aren't overwritten by later reads
+    variable_set_to!(names, engine, "MAKE_VERSION", "mine");
+
+    let variables = names.variable_name(".VARIABLES").unwrap();
+    let database = engine.database.clone();
+    let variables = database
+        .get_variable(variables)
+        .unwrap()
+        .expand(&mut names, &mut engine);
+    let variables: Vec<&str> = variables.split(' ').collect();
+    for name in &[
+        ".FEATURES",
+        ".VARIABLES",
+        "CURDIR",
+        "after",
+        "part_dir",
+        "top_dir",
+    ] {
+        assert!(variables.contains(name), "{} missing from .VARIABLES", name);
+    }
+}
+
+#[test]
+fn special_variables_from_environment() {
+    use hastur::Origin;
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+    engine.make_level = 2;
+    engine.import_environment(
+        &mut names,
+        vec![
+            ("CURDIR", "/elsewhere"),
+            ("MAKELEVEL", "1"),
+            ("MAKE", "env-make"),
+        ],
+        false,
+    );
+
+    engine
+        .read_makefile(&mut names, &mut "".as_bytes(), "Makefile")
+        .unwrap();
+
+    // CURDIR and MAKELEVEL replace the environment's values; other special
+    // variables defer to it
+    variable_set_to!(names, engine, "CURDIR", "/project");
+    variable_set_to!(names, engine, "MAKELEVEL", "2");
+    variable_set_to!(names, engine, "MAKE", "env-make");
+
+    let origin = |name| {
+        engine
+            .database
+            .variable_origin(names.variable_name(name).unwrap())
+    };
+    assert_eq!(origin("CURDIR"), Some(Origin::Default));
+    assert_eq!(origin("MAKEFILE_LIST"), Some(Origin::File));
+    assert_eq!(origin(".VARIABLES"), Some(Origin::Default));
+}
+
+#[test]
+fn remaking_missing_includes() {
+    use hastur::{MemoryFileSystem, RemakeAction};
+    use std::sync::Arc;
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.add_file("/project/main.c", "");
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+    engine.filesystem = Arc::new(filesystem);
+    engine.remake_makefiles = true;
+
+    let makefile = "
+-include main.d
+include config.mk
+include missing.mk
+-include optional.mk
+%.d: %.c
+\tcc -MM $< > $@
+config.mk:
+\techo 'mode := generated' > $@
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    let missing: Vec<&str> = engine
+        .missing_includes()
+        .iter()
+        .map(|include| include.name.as_str())
+        .collect();
+    assert_eq!(
+        missing,
+        vec!["main.d", "config.mk", "missing.mk", "optional.mk"]
+    );
+
+    let plan = engine.remake_plan(&names);
+    let actions: Vec<RemakeAction> = plan.makefiles.iter().map(|(_, action)| *action).collect();
+    assert_eq!(
+        actions,
+        vec![
+            RemakeAction::Build(names.file_name("%.d").unwrap()),
+            RemakeAction::Build(names.file_name("config.mk").unwrap()),
+            RemakeAction::Fail,
+            RemakeAction::Ignore,
+        ]
+    );
+    assert!(plan.needs_restart());
+    assert_eq!(
+        plan.to_build().collect::<Vec<_>>(),
+        vec!["main.d", "config.mk"]
+    );
+    assert_eq!(
+        plan.failures()
+            .map(|include| include.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["missing.mk"]
+    );
+
+    // Supply what the rules would have generated, and read everything again
+    engine.restart(vec![
+        ("main.d", "main.o: main.c main.h\n"),
+        ("config.mk", "mode := generated\n"),
+        ("missing.mk", "late := yes\n"),
+    ]);
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    assert_eq!(engine.restarts(), 1);
+    variable_set_to!(names, engine, "MAKE_RESTARTS", "1");
+    variable_set_to!(names, engine, "mode", "generated");
+    variable_set_to!(names, engine, "late", "yes");
+    variable_set_to!(
+        names,
+        engine,
+        "MAKEFILE_LIST",
+        "Makefile main.d config.mk missing.mk"
+    );
+    let main_o = names.file_name("main.o").unwrap();
+    assert_eq!(
+        engine
+            .database
+            .get_rule(main_o)
+            .unwrap()
+            .dependencies()
+            .len(),
+        2
+    );
+    assert_eq!(
+        engine
+            .missing_includes()
+            .iter()
+            .map(|include| include.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["optional.mk"]
+    );
+    assert!(!engine.remake_plan(&names).needs_restart());
+}
+
+#[test]
+fn restart_resets_reading_state() {
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+
+    let makefile = "
+.RECIPEPREFIX = >
+.SECONDEXPANSION:
+vpath %.c src
+VPATH = lib
+GPATH = lib
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+    assert_eq!(engine.command_char, '>');
+    assert!(engine.second_expansion);
+    assert!(!engine.patterned_vpaths.is_empty());
+    assert!(!engine.vpaths.is_empty());
+    assert!(!engine.gpaths.is_empty());
+
+    engine.restart(Vec::<(&str, &str)>::new());
+    assert_eq!(engine.command_char, '\t');
+    assert!(!engine.second_expansion);
+    assert!(engine.patterned_vpaths.is_empty());
+    assert!(engine.vpaths.is_empty());
+    assert!(engine.gpaths.is_empty());
+    assert!(names
+        .variable_name("VPATH")
+        .and_then(|vpath| engine.database.get_variable(vpath))
+        .is_none());
+}
+
+#[test]
+fn remaking_with_pattern_rules() {
+    use hastur::{MemoryFileSystem, RemakeAction};
+    use std::sync::Arc;
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.add_file("/project/conf.in", "");
+    filesystem.add_file("/project/gen.in", "");
+
+    let mut engine = Engine::default();
+    let mut names = NameCache::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+    engine.filesystem = Arc::new(filesystem);
+    engine.remake_makefiles = true;
+
+    let makefile = "
+-include foo.d
+-include conf.mk
+-include gen.mk
+%.d: %.c
+\tcc -MM $< > $@
+co%.mk: co%.in
+\tcp $< $@
+%nf.mk: %nf.in
+\tcp $< $@
+%.mk: %.tmpl
+\tcp $< $@
+%.tmpl: %.in
+\tcp $< $@
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    let plan = engine.remake_plan(&names);
+    let actions: Vec<RemakeAction> = plan.makefiles.iter().map(|(_, action)| *action).collect();
+    assert_eq!(
+        actions,
+        vec![
+            // There's no foo.c, so `%.d` doesn't apply
+            RemakeAction::Ignore,
+            // The first pattern rule that applies wins
+            RemakeAction::Build(names.file_name("co%.mk").unwrap()),
+            // gen.tmpl can be made from gen.in
+            RemakeAction::Build(names.file_name("%.mk").unwrap()),
+        ]
+    );
+}
@@ -0,0 +1 @@
+found := first
@@ -0,0 +1,2 @@
+include first/searched.mk
+include second/searched.mk
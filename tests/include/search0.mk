@@ -0,0 +1,2 @@
+include searched.mk
+-include missing.mk
@@ -0,0 +1,156 @@
+#[macro_use]
+mod util_macros;
+
+use hastur::{Engine, MemoryFileSystem, NameCache};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+fn at(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+fn engine_with_files(files: &[(&str, u64)]) -> Engine {
+    let mut filesystem = MemoryFileSystem::new();
+    for (name, modified) in files {
+        filesystem.add_file(name, "");
+        assert!(filesystem.set_modified(name, at(*modified)));
+    }
+
+    let mut engine = Engine::default();
+    engine.working_directory = "/project".into();
+    engine.search_default_include_directories = false;
+    engine.filesystem = Arc::new(filesystem);
+    engine
+}
+
+#[test]
+fn directory_search() {
+    let mut engine = engine_with_files(&[
+        ("/project/main.c", 1),
+        ("/project/src/main.c", 1),
+        ("/project/src/util.c", 1),
+        ("/project/lib/util.c", 1),
+        ("/project/lib/extra.c", 1),
+        ("/project/include/util.h", 1),
+    ]);
+    let mut names = NameCache::default();
+    let makefile = "VPATH = lib : src
+vpath %.h include
+vpath util.% src
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+    assert_eq!(
+        engine.vpaths,
+        vec![PathBuf::from("lib"), PathBuf::from("src")]
+    );
+
+    // Files that exist under their own names aren't searched for
+    assert_eq!(
+        engine.get_path_for_filename("main.c"),
+        PathBuf::from("main.c")
+    );
+    assert_eq!(
+        engine.get_path_for_filename("/project/src/util.c"),
+        PathBuf::from("/project/src/util.c")
+    );
+    // Patterned vpaths are searched before VPATH
+    assert_eq!(
+        engine.get_path_for_filename("util.c"),
+        PathBuf::from("src/util.c")
+    );
+    assert_eq!(
+        engine.get_path_for_filename("extra.c"),
+        PathBuf::from("lib/extra.c")
+    );
+    assert_eq!(
+        engine.get_path_for_filename("util.h"),
+        PathBuf::from("include/util.h")
+    );
+    assert_eq!(
+        engine.get_path_for_filename("missing.c"),
+        PathBuf::from("missing.c")
+    );
+
+    // An empty VPATH clears the search path
+    engine
+        .read_makefile(&mut names, &mut "VPATH :=\n".as_bytes(), "Makefile")
+        .unwrap();
+    assert!(engine.vpaths.is_empty());
+    assert_eq!(
+        engine.get_path_for_filename("extra.c"),
+        PathBuf::from("extra.c")
+    );
+    variable_set_to!(names, engine, "VPATH", "");
+}
+
+#[test]
+fn keeping_found_names() {
+    let mut engine = engine_with_files(&[
+        ("/project/src/main.c", 10),
+        ("/project/src/util.c", 30),
+        ("/project/out/main.o", 20),
+        ("/project/out/util.o", 20),
+        ("/project/out/app", 40),
+        ("/project/gen/version.o", 5),
+        ("/project/gen/version.c", 10),
+    ]);
+    let mut names = NameCache::default();
+    let makefile = "VPATH = src out gen
+GPATH = gen
+.PHONY: clean
+app: main.o util.o
+main.o: main.c
+util.o: util.c
+version.o: version.c
+clean:
+";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+    assert_eq!(engine.gpaths, vec![PathBuf::from("gen")]);
+
+    let resolve = |name: &str| engine.resolve_file_path(&names, name);
+    // Up to date, so the name found by the search is used
+    assert_eq!(resolve("main.o"), PathBuf::from("out/main.o"));
+    assert_eq!(resolve("main.c"), PathBuf::from("src/main.c"));
+    // util.c is newer than util.o, so util.o is rebuilt in the current
+    // directory, and so is everything that depends on it
+    assert_eq!(resolve("util.o"), PathBuf::from("util.o"));
+    assert_eq!(resolve("app"), PathBuf::from("app"));
+    // Files found in GPATH keep their names even when rebuilt
+    assert_eq!(resolve("version.o"), PathBuf::from("gen/version.o"));
+    // Phony targets are never searched for
+    assert_eq!(resolve("clean"), PathBuf::from("clean"));
+}
+
+#[test]
+fn shared_prerequisites() {
+    let mut engine = engine_with_files(&[("/project/out/top", 20), ("/project/src/base", 10)]);
+    let mut names = NameCache::default();
+    // Each level depends twice on the next, so the graph has 2^40 paths
+    let mut makefile = String::from("VPATH = src out\ntop: level0\n");
+    for level in 0..40 {
+        makefile += &format!(
+            "level{0}: part{0}a part{0}b\npart{0}a part{0}b: level{1}\n",
+            level,
+            level + 1
+        );
+    }
+    makefile += "level40: base\n";
+    engine
+        .read_makefile(&mut names, &mut makefile.as_bytes(), "Makefile")
+        .unwrap();
+
+    // The levels don't exist, so everything above them is rebuilt
+    assert_eq!(
+        engine.resolve_file_path(&names, "top"),
+        PathBuf::from("top")
+    );
+    assert_eq!(
+        engine.resolve_file_path(&names, "base"),
+        PathBuf::from("src/base")
+    );
+}